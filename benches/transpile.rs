@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// A file of `n` copies of some everyday rust, each with its own names.
+fn synthetic(n: usize) -> String {
+    let mut src = String::new();
+    for i in 0..n {
+        src.push_str(&format!(
+            r#"
+struct Point{i} {{
+    x: i32,
+    y: i32,
+}}
+
+impl Point{i} {{
+    fn new(x: i32, y: i32) -> Point{i} {{
+        Point{i} {{ x, y }}
+    }}
+
+    fn len(&self) -> i32 {{
+        // manhattan, no sqrt
+        self.x.abs() + self.y.abs()
+    }}
+}}
+
+fn walk{i}(steps: &[i32], limit: i32) -> Option<i32> {{
+    let mut total = 0;
+    for (i, step) in steps.iter().enumerate() {{
+        if *step > limit {{
+            return None;
+        }}
+        total += step * i as i32;
+    }}
+    let point = Point{i}::new(total, limit);
+    match point.len() {{
+        0 => None,
+        n if n % 2 == 0 => Some(n / 2),
+        n => {{
+            println!("odd {{}} at {{}}", n, "walk{i}");
+            Some(n)
+        }}
+    }}
+}}
+"#,
+            i = i
+        ));
+    }
+    src
+}
+
+fn transpile(c: &mut Criterion) {
+    let options = rua::Options::default();
+    let mut group = c.benchmark_group("transpile");
+    for n in [10, 100, 1000] {
+        let src = synthetic(n);
+        group.throughput(Throughput::Bytes(src.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &src, |b, src| {
+            b.iter(|| rua::transpile("bench.rs", src, &options))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, transpile);
+criterion_main!(benches);
@@ -1,3 +1,5 @@
+#[inline]
+#[must_use]
 fn add(a: i32, b: i32) -> i32 {
     return a + b;
 }
@@ -22,3 +24,1213 @@ fn main() {
         }
     }
 }
+
+fn describe(n: i32) -> &'static str {
+    // declared without a value, lua's `local s` starts out as nil
+    let s: &str;
+    if n > 0 {
+        s = "positive";
+    } else if n < 0 {
+        s = "negative";
+    } else {
+        s = "zero";
+    }
+    return s;
+}
+
+fn noop() {}
+
+fn wait(ready: bool) {
+    if ready {
+    } else {
+    }
+    for _ in 0..3 {}
+    loop {}
+}
+
+struct Counter {
+    count: i32,
+}
+
+impl Counter {
+    const STEP: i32 = 1;
+
+    fn new() -> Self {
+        Self { count: 0 }
+    }
+
+    fn bump(&mut self) -> i32 {
+        self.count += Self::STEP;
+        self.count
+    }
+
+    fn starting_at(count: i32) -> Self {
+        // associated fns are called with a dot, methods take self first
+        let mut counter = Self::new();
+        counter.count = count - Self::STEP;
+        Counter::bump(&mut counter);
+        counter
+    }
+}
+
+fn div_rem(a: i32, b: i32) -> (i32, i32) {
+    (a / b, a % b)
+}
+
+fn halves() {
+    // tuples are returned as multiple values, by fns and closures alike
+    let (q, r) = div_rem(7, 2);
+    let split = |n: i32| (n / 2, n - n / 2);
+    let (a, b) = split(q + r);
+}
+
+fn skip_zeros(v: &[i32]) {
+    // match arms lower to an if chain, so break and continue still
+    // control the loop around it
+    for x in v {
+        match *x {
+            0 => continue,
+            n if n < 0 => break,
+            n => println!("{}", n),
+        }
+    }
+}
+
+fn dispatch(cmd: &str) -> i32 {
+    match cmd {
+        "quit" | "exit" => 0,
+        "say \"hi\"" => 1,
+        other => {
+            println!("unknown command {}", other);
+            2
+        }
+    }
+}
+
+fn grade(score: u32) -> &'static str {
+    match score {
+        s @ 90...100 if s % 10 == 0 => "round A",
+        90...100 => "A",
+        s @ 50 | s @ 60 => "borderline",
+        0..50 => "fail",
+        _ => "pass",
+    }
+}
+
+fn total(mut start: i32, extra: Option<i32>) -> i32 {
+    let mut sum = 0;
+    let ref step = start;
+    let (mut lo, ref mut hi) = (0, 10);
+    let add = |mut acc: i32, n: i32| {
+        acc += n;
+        acc
+    };
+    if let Some(ref n) = extra {
+        sum = add(sum, *n);
+    }
+    sum + start + *step + lo + *hi
+}
+
+fn adders() {
+    // lua closures capture like rust ones, so factories work as they are
+    let add = |x: i32| move |y: i32| x + y;
+    let counter = || {
+        let mut n = 0;
+        move || {
+            n += 1;
+            n
+        }
+    };
+    let next = counter();
+    println!("{} {}", add(1)(2), next());
+}
+
+fn triangle(n: i32) -> i32 {
+    let mut sum = 0;
+    for i in 1..=n {
+        sum += i;
+    }
+    let range = 1..=n;
+    sum
+}
+
+fn countdown(n: i32) {
+    for i in (1..=n).rev() {
+        println!("{}...", i);
+    }
+    for i in (0..n).rev() {
+        println!("{}", i);
+    }
+}
+
+fn count_pairs(mut n: i32) -> i32 {
+    // rust's do while, a loop ending in a conditional break
+    let mut count = 0;
+    loop {
+        count += 1;
+        n -= 2;
+        if n <= 0 {
+            break;
+        }
+    }
+    count
+}
+
+fn twice<F: Fn(i32) -> i32>(f: F, x: i32) -> i32 {
+    f(f(x))
+}
+
+fn callbacks() {
+    // named functions are values in lua too
+    let quadruple = twice(double, 5);
+    let pick = std::cmp::max;
+    println!("{} {}", quadruple, pick(1, 2));
+}
+
+fn double(x: i32) -> i32 {
+    x * 2
+}
+
+fn validate(scores: &[i32], name: &str) -> bool {
+    let failing = scores.iter().find(|s| **s < 50);
+    let curved: Vec<i32> = scores
+        .iter()
+        .filter_map(|s| if *s > 0 { Some(s + 5) } else { None })
+        .collect();
+    let named = name.chars().all(|c| c != ' ');
+    named && !scores.iter().any(|s| *s > 100)
+}
+
+fn report(scores: &[i32]) -> String {
+    use std::fmt::Write;
+    let mut out = String::new();
+    write!(out, "scores: ").unwrap();
+    for score in scores {
+        write!(out, "{} ", score)?;
+    }
+    writeln!(out, "({} total)", scores.len()).unwrap();
+    writeln!(out).unwrap();
+    out
+}
+
+const DEBUG_LOG: bool = false;
+const MAX_LEVEL: i32 = if DEBUG_LOG { 99 } else { 10 };
+
+fn describe_let(n: i32) -> &'static str {
+    // assigned in each branch instead of going through a function
+    let size = match n {
+        0 => "none",
+        1..=9 => "few",
+        _ => "many",
+    };
+    let parity = if n % 2 == 0 { "even" } else { "odd" };
+    size
+}
+
+#[derive(Default)]
+struct Settings {
+    title: String,
+    volume: i32,
+    muted: bool,
+    history: Vec<i32>,
+}
+
+fn settings() -> Settings {
+    let mut settings = Settings::default();
+    let fallback: Settings = Default::default();
+    settings.volume = fallback.volume + 5;
+    settings
+}
+
+fn rot13(c: char) -> char {
+    let code = c as u32;
+    if code >= 'a' as u32 && code <= 'z' as u32 {
+        ((code - 'a' as u32 + 13) % 26 + 'a' as u32) as u8 as char
+    } else {
+        c
+    }
+}
+
+#[test]
+fn rot13_round_trips() {
+    assert_eq!(rot13(rot13('q')), 'q');
+    assert_ne!(rot13('a'), 'a');
+    assert!(rot13('!') == '!', "only letters move");
+    for c in "abc".chars() {
+        assert!(rot13(c) != c, "{} didn't move", c);
+        assert_eq!(rot13(rot13(c)), c, "{} didn't come back", c);
+    }
+}
+
+fn split_header(line: &str, fields: &[i32]) -> i32 {
+    let tag = &line[..3];
+    let body = &line[4..];
+    let rest = &fields[1..];
+    if tag == "hdr" {
+        rest.len() as i32 + body.len() as i32
+    } else {
+        fields[0..=1].len() as i32
+    }
+}
+
+fn shout(text: &str) -> String {
+    let letters: String = text.chars().filter(|c| *c != ' ').collect();
+    letters.chars().map(rot13).collect::<String>()
+}
+
+fn greet(name: &str) {
+    // lua only allows return at the end of a block, rua makes one for it
+    if name.is_empty() {
+        return;
+    }
+    println!("hello {}", name);
+    return;
+}
+
+fn compare(a: &str, b: &str, x: f64, y: f64) -> i32 {
+    let by_name = a.cmp(&b);
+    let by_size = x.partial_cmp(&y).unwrap();
+    if by_name != by_size {
+        1
+    } else {
+        0
+    }
+}
+
+fn leaderboard(mut scores: Vec<i32>, mut names: Vec<String>) -> Vec<String> {
+    scores.sort();
+    scores.sort_by(|a, b| b.cmp(a));
+    names.sort_by_key(|name| name.len());
+    names
+}
+
+fn drain(mut stack: Vec<i32>) -> i32 {
+    let mut total = 0;
+    while let Some(top) = stack.pop() {
+        total += top;
+    }
+    let mut n = total;
+    while n > 100 {
+        n -= 100;
+        // a loop body's value goes nowhere, it's not returned
+        n
+    }
+    for i in 0..3 {
+        i * 2
+    }
+    n
+}
+
+const SAVE_DIR: &str = r"C:\games\rua\saves";
+const QUOTE_PATTERN: &str = r#"^"(.-)"$"#;
+
+fn banner(title: &str) -> String {
+    format!(
+        "+--------+
+| {} |
++--------+",
+        title
+    )
+}
+
+#[lua(optional)]
+fn spawn(x: i32, speed: Option<i32>, label: Option<&str>) -> i32 {
+    x + speed.unwrap_or(1)
+}
+
+fn spawn_all() {
+    spawn(0, None, None);
+    spawn(1, Some(2), None);
+    spawn(2, None, Some("fast"));
+}
+
+fn top_three(scores: &[i32], width: usize) -> Vec<i32> {
+    let mut row: Vec<i32> = std::iter::repeat(0).take(width).collect();
+    let best: Vec<i32> = scores.iter().filter(|s| **s > 0).take(3).collect();
+    for i in 0..best.len() {
+        row[i] = best[i];
+    }
+    row
+}
+
+enum Shape {
+    Circle(f64),
+    Rect { w: f64, h: f64 },
+    Empty,
+}
+
+impl Shape {
+    fn area(&self) -> f64 {
+        match self {
+            Shape::Circle(r) => 3.0 * r * r,
+            Shape::Rect { w, h } => w * h,
+            Shape::Empty => 0.0,
+        }
+    }
+}
+
+fn count_round(shapes: &[Shape]) -> i32 {
+    let mut n = 0;
+    for shape in shapes {
+        if matches!(shape, Shape::Circle(_)) || shape.is_rect() {
+            n += 1;
+        }
+    }
+    n
+}
+
+fn shadowing(x: i32) -> i32 {
+    let count = "5";
+    let count: i32 = count.parse().unwrap();
+    let x = if x > count { x } else { count };
+    let x = x * 2;
+    x
+}
+
+fn title(name: &String, tags: &Vec<String>) -> String {
+    let name = name.as_str().trim();
+    if tags.as_slice().is_empty() {
+        return name.to_uppercase();
+    }
+    format!("{} [{}]", name, tags.as_slice()[0].as_str())
+}
+
+struct Machine {
+    state: i32,
+    events: Vec<i32>,
+}
+
+impl Machine {
+    fn advance(&mut self, next: i32) -> i32 {
+        let previous = std::mem::replace(&mut self.state, next);
+        previous
+    }
+
+    fn flush(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[allow(dead_code)]
+struct Stack<T> {
+    items: Vec<T>,
+}
+
+impl<T: Clone> Stack<T> {
+    fn new() -> Self {
+        Stack { items: Vec::new() }
+    }
+
+    #[inline(always)]
+    fn peek(&self) -> Option<&T> {
+        if self.items.is_empty() {
+            return None;
+        }
+        Some(&self.items[self.items.len() - 1])
+    }
+
+    fn lengths<F: Fn(&T) -> usize>(&self, len: F) -> Vec<usize> {
+        self.items.iter().map(len).collect()
+    }
+}
+
+fn bonus(score: i32, streak: i32) -> i32 {
+    let doubled = (streak > 3).then(|| score * 2);
+    let flat = (score > 100).then_some(10);
+    doubled.unwrap_or(score) + flat.unwrap_or(0)
+}
+
+fn settle(grid: &[i32], limit: i32) -> i32 {
+    let mut moved = 0;
+    for cell in grid {
+        match *cell {
+            0 => {}
+            n if n > limit => {
+                let mut left = n;
+                while left > limit {
+                    if left % 2 == 0 {
+                        left /= 2;
+                    } else {
+                        loop {
+                            left -= 1;
+                            if left % 2 == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    moved += 1;
+                }
+            }
+            _ => moved += 1,
+        }
+    }
+    moved
+}
+
+fn log_move(player: &str, from: i32, to: i32) {
+    println!("{0} moved from {1} to {2}, go {0}!", player, from, to);
+    println!("{player}: {from} -> {to} ({steps} steps)", steps = to - from);
+}
+
+fn new_board(rows: usize, cols: usize) -> Vec<Vec<i32>> {
+    let mut board = vec![vec![0; cols]; rows];
+    let corners = vec![1, 2, 3, 4];
+    board[0][0] = corners[0];
+    board
+}
+
+fn word_counts(words: &[&str]) -> HashMap<&str, i32> {
+    let mut counts = HashMap::new();
+    for word in words {
+        *counts.entry(*word).or_insert(0) += 1;
+    }
+    let mut by_length = HashMap::new();
+    for word in words {
+        by_length.entry(word.len()).or_insert(Vec::new()).push(*word);
+    }
+    counts
+}
+
+fn is_palindrome(word: &str) -> bool {
+    let reversed: String = word.chars().rev().collect();
+    let mut vowels = 0;
+    for c in word.chars().rev() {
+        if "aeiou".contains(c) {
+            vowels += 1;
+        }
+    }
+    vowels > 0 && reversed == word
+}
+
+fn by_score_desc(a: &i32, b: &i32) -> Ordering {
+    match a.cmp(b) {
+        Ordering::Less => Ordering::Greater,
+        Ordering::Greater => Ordering::Less,
+        Ordering::Equal => Ordering::Equal,
+    }
+}
+
+fn fibonacci(n: i32) -> i32 {
+    let (mut a, mut b) = (0, 1);
+    for _ in 0..n {
+        (a, b) = (b, a + b);
+    }
+    a
+}
+
+fn first_negative(values: &[i32]) -> Option<usize> {
+    for i in 0..values.len() {
+        if values[i] == 0 {
+            continue;
+        }
+        if values[i] < 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+fn closest(points: &[f64], target: f64) -> f64 {
+    let mut best = f64::INFINITY;
+    let mut best_distance = f64::MAX;
+    for p in points {
+        let distance = (p - target).abs();
+        if distance < best_distance {
+            best = *p;
+            best_distance = distance;
+        }
+    }
+    if best == f64::INFINITY { f64::NAN } else { best }
+}
+
+struct Cell {
+    row: i32,
+    col: i32,
+}
+
+fn on_diagonal(cell: Option<Cell>, moves: &[(i32, Option<i32>)]) -> bool {
+    if let Some(Cell { row, col: 0 }) = cell {
+        return row == 0;
+    }
+    let mut jumps = 0;
+    for (from, to) in moves {
+        if matches!(to, Some(n) if *n > from + 1) {
+            jumps += 1;
+        }
+    }
+    match cell {
+        Some(Cell { row, col }) if row == col => true,
+        _ => jumps > 0,
+    }
+}
+
+fn resolve(flag: Option<i32>, env: Option<i32>, file: Option<i32>, enabled: Option<bool>) -> i32 {
+    let chosen = flag.or(env).or_else(|| file);
+    let checked = enabled.and(chosen);
+    checked.unwrap_or_else(|| 80)
+}
+
+fn warn_once(warned: &mut bool, message: &str) {
+    if *warned {
+        return ();
+    }
+    *warned = true;
+    print(message);
+    ()
+}
+
+#[derive(Clone)]
+struct Particle {
+    x: f64,
+    speed: f64,
+}
+
+impl Particle {
+    fn resting() -> Particle {
+        Particle { x: 0.0, speed: 0.0 }
+    }
+}
+
+fn spawn_particles(n: usize) -> Vec<Particle> {
+    let mut sparks = [Particle { x: 0.0, speed: 1.0 }; 4];
+    let mut dust = vec![Particle::resting(); n];
+    sparks[0].x = 1.0;
+    dust[0].speed = sparks[0].x;
+    dust
+}
+
+trait Area {
+    fn area(&self) -> f64;
+
+    fn is_large(&self) -> bool {
+        self.area() > 100.0
+    }
+
+    fn threshold() -> f64 {
+        100.0
+    }
+}
+
+struct Square {
+    side: f64,
+}
+
+impl Area for Square {
+    fn area(&self) -> f64 {
+        self.side * self.side
+    }
+}
+
+impl Area for Particle {
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    fn is_large(&self) -> bool {
+        false
+    }
+}
+
+fn scan(values: &[i32], limit: i32, strict: bool) -> usize {
+    let mut i = 0;
+    let mut found = false;
+    while i < values.len() && !found {
+        found = values[i] > limit || strict && values[i] == limit;
+        i += 1;
+    }
+    i
+}
+
+fn announce(place: usize, name: &str) {
+    match place {
+        1 => print("winner", name),
+        2 | 3 => {
+            print("podium", name);
+        }
+        _ => {}
+    };
+    let medal = match place {
+        1 => "gold",
+        2 => "silver",
+        _ => "bronze",
+    };
+    if place <= 3 {
+        print(medal);
+    }
+}
+
+fn with_sentinels(values: &[i32]) -> Vec<i32> {
+    let framed: Vec<i32> = std::iter::once(0).chain(values.iter()).collect();
+    let doubled: Vec<i32> = framed.iter().map(|v| v * 2).chain(std::iter::once(-1)).collect();
+    doubled
+}
+
+fn is_ident_char(c: char) -> bool {
+    let lower = c >= 'a' && c <= 'z';
+    let upper = ('A'..='Z').contains(&c);
+    lower || upper || c == '_' || c.is_ascii_digit()
+}
+
+fn to_upper_ascii(c: char) -> char {
+    if c >= 'a' && c <= 'z' {
+        (c as u8 - b'a' + b'A') as char
+    } else {
+        c
+    }
+}
+
+fn neighbours(values: &[i32], i: usize) -> i32 {
+    let left = values.get(i - 1).copied().unwrap_or(0);
+    let right = match values.get(i + 1) {
+        Some(v) => *v,
+        None => 0,
+    };
+    left + right
+}
+
+fn sum_pair(pair: (i32, i32), scale: i32) -> i32 {
+    (pair.0 + pair.1) * scale
+}
+
+fn add_rem(q: i32, r: i32) -> i32 {
+    q + r
+}
+
+fn spread_and_pack(a: i32, b: i32) -> i32 {
+    // a tuple spreads into the last argument but stays a tuple elsewhere
+    let total = add_rem(div_rem(a, b));
+    let scaled = sum_pair(div_rem(a, b), 2);
+    let kept = div_rem(b, a);
+    total + scaled + kept.0
+}
+
+fn first_below(values: &[i32], limit: i32) -> i32 {
+    let found = 'search: {
+        for v in values {
+            if *v < limit {
+                break 'search *v;
+            }
+        }
+        limit
+    };
+    'report: {
+        if found == limit {
+            break 'report;
+        }
+        println!("found {}", found);
+    }
+    found
+}
+
+fn index_of(names: &[&str], target: &str) -> i32 {
+    // position is 0 based, like the indices it's used with
+    match names.iter().position(|name| *name == target) {
+        Some(i) => {
+            println!("{} is {}", target, names[i]);
+            i as i32
+        }
+        None => -1,
+    }
+}
+
+fn parity(n: u32) -> &'static str {
+    // nested fns are locals, declared before either is defined
+    fn even(n: u32) -> bool {
+        n == 0 || odd(n - 1)
+    }
+    fn odd(n: u32) -> bool {
+        n != 0 && even(n - 1)
+    }
+    if even(n) {
+        "even"
+    } else {
+        "odd"
+    }
+}
+
+fn totals(values: &[i32]) -> i32 {
+    let sum = values.iter().fold(0, |acc, v| acc + v);
+    let largest = values.iter().map(|v| v * 2).reduce(|a, b| a.max(b));
+    sum + largest.unwrap_or(0)
+}
+
+fn middle_sum(values: &[i32]) -> Option<i32> {
+    // ? returns nil from fns returning an Option
+    let first = values.get(0)?;
+    let total = first + values.get(1)? + values.get(2)?;
+    Some(total)
+}
+
+fn snapshot(buffer: &mut Vec<i32>, history: &mut Vec<Vec<i32>>) {
+    // copies, so later pushes don't change the history
+    history.push(buffer.clone());
+    let saved = buffer.as_slice().to_vec();
+    buffer.push(saved.len() as i32);
+}
+
+fn serve(requests: &mut Vec<i32>) {
+    // ends in a loop that never breaks, there's nothing to return after it
+    loop {
+        match requests.pop() {
+            Some(request) => println!("handling {}", request),
+            None => println!("idle"),
+        }
+    }
+}
+
+fn table_row(name: &str, score: f64, rank: i32) -> String {
+    let label = String::from(name);
+    let header = format!("{:-^20}", " scores ");
+    let row = format!("{:<10}|{:>6.1}|{:+}|{:03}", label, score, rank, rank);
+    let centered = format!("{:^8}|{:*>5}", label, rank);
+    format!("{}\n{}\n{}", header, row, centered)
+}
+
+fn describe_total(values: &[i32]) -> String {
+    // a match with one binding arm is a let, there's no if to test it
+    match values.iter().fold(0, |acc, v| acc + v) {
+        total => format!("total {}", total),
+    }
+}
+
+fn checksum(packet: &str) -> u32 {
+    let bytes = packet.as_bytes();
+    let mut sum = bytes.len() as u32;
+    for b in packet.bytes() {
+        sum = (sum * 31 + b as u32) % 65521;
+    }
+    if bytes[0] == b'#' {
+        sum += 1;
+    }
+    sum
+}
+
+fn mix(hash: u8, byte: u8, steps: usize) -> u8 {
+    // wrapping and saturating ops use the bounds of the types we can see
+    let mixed = hash.wrapping_mul(31).wrapping_add(byte);
+    let remaining = steps.saturating_sub(1);
+    let level: i8 = -100;
+    let lowered = level.saturating_sub(50);
+    if remaining > 0 && lowered < 0 {
+        mixed
+    } else {
+        hash
+    }
+}
+
+fn dedup(values: &[i32]) -> Vec<i32> {
+    // sets are tables with the elements as keys
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    for v in values {
+        if !seen.contains(v) {
+            seen.insert(*v);
+            unique.push(*v);
+        }
+    }
+    seen.remove(&0);
+    println!("{} distinct", seen.len());
+    unique
+}
+
+struct HttpRequest {
+    bodyLen: usize,
+}
+
+impl HttpRequest {
+    // names keep the casing they're written with
+    fn newRequest(bodyLen: usize) -> HttpRequest {
+        HttpRequest { bodyLen }
+    }
+}
+
+fn parse_HTTP(len: usize) -> usize {
+    let request = HttpRequest::newRequest(len);
+    request.bodyLen
+}
+
+fn drain_positive(stack: &mut Vec<i32>) -> i32 {
+    let mut total = 0;
+    while let Some(top) = stack.pop() {
+        if top < 0 {
+            break;
+        }
+        total += top;
+    }
+    total
+}
+
+fn sum_nonzero(pairs: &[(i32, i32)]) -> i32 {
+    let mut total = 0;
+    for (x, y) in pairs {
+        if *x == 0 {
+            continue;
+        }
+        total += x * y;
+    }
+    total
+}
+
+fn first_over(grid: &[Vec<i32>], limit: i32) -> Option<i32> {
+    // lua's break and continue only see the innermost loop, labels jump
+    let mut found = None;
+    'rows: for row in grid {
+        for cell in row {
+            if *cell < 0 {
+                continue 'rows;
+            }
+            if *cell > limit {
+                found = Some(*cell);
+                break 'rows;
+            }
+        }
+    }
+    found
+}
+
+struct Celsius {
+    degrees: f64,
+}
+
+impl From<f64> for Celsius {
+    fn from(degrees: f64) -> Self {
+        Celsius { degrees }
+    }
+}
+
+fn warmest(readings: &[f64]) -> Celsius {
+    // into is the value itself, unless the type it's for has a From impl
+    let label: String = "warmest".into();
+    let mut best: Celsius = 0.0.into();
+    for r in readings {
+        if *r > best.degrees {
+            best = Celsius::from(*r);
+        }
+    }
+    println!("{}: {}", label, best.degrees);
+    best.degrees.into()
+}
+
+struct Span(usize, usize);
+
+impl Span {
+    fn width(&self) -> usize {
+        self.1 - self.0
+    }
+}
+
+fn widest(bounds: &[(usize, usize)]) -> usize {
+    // instances get their methods from the type's table
+    let mut widest = Span(0, 0);
+    for (lo, hi) in bounds {
+        let span = Span(*lo, *hi);
+        if span.width() > widest.width() {
+            widest = span;
+        }
+    }
+    widest.width()
+}
+
+fn suit_name(suit: u8) -> &'static str {
+    // the match is exhaustive for the suits there are
+    match suit {
+        0 => "clubs",
+        1 => "diamonds",
+        2 => "hearts",
+        3 => "spades",
+        _ => unreachable!("there are only 4 suits, not {}", suit),
+    }
+}
+
+fn check_hand(cards: &[u8]) {
+    if cards.is_empty() {
+        assert!(false, "a hand needs cards");
+    }
+    for card in cards {
+        if *card >= 52 {
+            unreachable!();
+        }
+    }
+}
+
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+struct Window {
+    title: String,
+    size: Size,
+    visible: bool,
+}
+
+fn open_window(title: String, w: u32, h: u32) -> Window {
+    // shorthand fields take the value of the local they're named after
+    let visible = w > 0 && h > 0;
+    Window {
+        title,
+        size: Size { w, h },
+        visible,
+    }
+}
+
+fn draw_order(layers: &Vec<u32>) -> Vec<u32> {
+    // back to front, the last layer is drawn first
+    let mut order = Vec::new();
+    for layer in layers.iter().rev() {
+        order.push(*layer);
+    }
+    order
+}
+
+fn time_sum(values: &[u64]) -> u64 {
+    // Instant::now() is os.clock(), elapsed times are seconds
+    let start = std::time::Instant::now();
+    let mut sum = 0;
+    for v in values {
+        sum += v;
+    }
+    let elapsed = start.elapsed();
+    println!("summed in {}ms ({}s)", elapsed.as_millis(), elapsed.as_secs_f64());
+    sum
+}
+
+fn sum_readings(readings: &[Option<u32>]) -> u32 {
+    // returns in the arms return from the fn, not a wrapper around the match
+    let mut sum = 0;
+    for reading in readings {
+        let reading = match reading {
+            Some(reading) => *reading,
+            None if sum == 0 => continue,
+            None => return 0,
+        };
+        sum += reading;
+    }
+    sum
+}
+
+// mutable statics are globals every fn shares
+static mut NEXT_ID: u32 = 1;
+static ID_PREFIX: &str = "item-";
+
+fn next_id() -> String {
+    unsafe {
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        format!("{}{}", ID_PREFIX, id)
+    }
+}
+
+fn scaled(values: &[i32], factor: i32) -> Vec<i32> {
+    // typed closures lose their types, block bodies return their tail
+    let scale = |x: i32| -> i32 {
+        if x < 0 {
+            return 0;
+        }
+        let y = x * factor;
+        y
+    };
+    let mut out = Vec::new();
+    for v in values {
+        out.push(scale(*v));
+    }
+    out
+}
+
+fn describe_score(best: Option<u32>, name: Option<&str>) -> String {
+    // the default is only used for None, even if f returns false
+    let points = best.map_or(0, |b| b * 10);
+    let passed = best.map_or(false, |b| b > 50);
+    let width = name.map_or_else(|| 4, |n| n.len());
+    format!("{} {} {}", points, passed, width)
+}
+
+fn print_sections(sections: &[&str]) {
+    for section in sections {
+        println!("{}", section);
+        // a blank line between sections
+        println!();
+    }
+}
+
+fn spread(a: f64, b: f64, counts: &Vec<u32>) -> String {
+    // receivers lua can't call methods on directly get parentheses
+    let gap = (a - b).abs().max(0.5);
+    let label = 5.to_string();
+    format!("{} {} {} {}", gap, counts.len(), label, "done".to_uppercase())
+}
+
+fn repeat_word(word: &str, times: usize) -> String {
+    // capacities are only hints, lua tables and strings grow as needed
+    let mut words = Vec::with_capacity(times);
+    let mut out = String::with_capacity(word.len() * times);
+    for _ in 0..times {
+        words.push(word);
+        write!(out, "{}", word).unwrap();
+    }
+    out
+}
+
+#[derive(Default)]
+struct Request {
+    url: String,
+    retries: u32,
+    timeout: u32,
+}
+
+impl Request {
+    fn to(url: &str) -> Self {
+        Request {
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // builder methods give back the request they changed
+    fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    fn with_timeout(self, timeout: u32) -> Self {
+        Request { timeout, ..self }
+    }
+}
+
+fn fetch_slow(url: &str) -> u32 {
+    let request = Request::to(url).with_retries(3).with_timeout(30);
+    request.retries * request.timeout
+}
+
+fn grid_cells(rows: usize) -> usize {
+    // const blocks are plain blocks, lua works them out at runtime
+    let per_row = const { 4 * 4 };
+    let border = const {
+        let side = 4;
+        side * 2
+    };
+    rows * per_row + border
+}
+
+fn cell_separator(wide: bool) -> String {
+    // escapes give the same characters in lua, invisible ones stay escaped
+    let tab = '\t';
+    let nbsp = '\u{a0}';
+    let bullet = '\u{2022}';
+    let sep = if wide { nbsp } else { tab };
+    format!("{}{}{}", sep, bullet, sep)
+}
+
+fn peek_is_digit(src: &str, pos: usize) -> bool {
+    // the n-th character counts characters, not bytes
+    match src.chars().nth(pos) {
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    }
+}
+
+fn total_parsed(fields: &[&str]) -> i64 {
+    // turbofish types pick the parse, and are otherwise left out
+    let counts = fields
+        .iter()
+        .map(|f| f.parse::<i64>().unwrap_or(0))
+        .collect::<Vec<_>>();
+    let scale = fields.iter().map(|f| f.len()).product::<usize>();
+    counts.iter().sum::<i64>() + scale as i64
+}
+
+fn parse_level(name: &str) -> Result<u8, String> {
+    // results are the value, or nil and the error
+    match name {
+        "low" => Ok(1),
+        "high" => Ok(3),
+        _ => Err(format!("unknown level {}", name)),
+    }
+}
+
+fn level_or_default(name: &str) -> u8 {
+    if let Err(e) = parse_level(name) {
+        println!("{}", e);
+    }
+    if let Ok(level) = parse_level(name) {
+        level
+    } else {
+        2
+    }
+}
+
+mod geometry {
+    // siblings call each other without the module in front
+    pub fn perimeter(w: i32, h: i32) -> i32 {
+        double(w) + double(h)
+    }
+
+    fn double(x: i32) -> i32 {
+        x * FACTOR
+    }
+
+    const FACTOR: i32 = 2;
+
+    pub mod units {
+        pub fn to_cm(mm: i32) -> i32 {
+            mm / 10
+        }
+    }
+
+    pub struct Frame {
+        pub w: i32,
+        pub h: i32,
+    }
+}
+
+fn frame(w: i32, h: i32) -> geometry::Frame {
+    geometry::Frame { w, h }
+}
+
+fn frame_cm(w: i32, h: i32) -> i32 {
+    geometry::units::to_cm(geometry::perimeter(w, h))
+}
+
+fn weighted_total(values: &[i32], weights: &[i32]) -> i32 {
+    let mut total = 0;
+    // zip stops at the end of the shorter one
+    for (value, weight) in values.iter().zip(weights.iter()) {
+        total += value * weight;
+    }
+    total
+}
+
+fn ruled_title(title: &str, width: usize) -> String {
+    // lua strings can't change, appending makes a new one
+    let mut line = String::new();
+    for _ in 0..width {
+        line.push('=');
+    }
+    let mut out = line.clone() + "\n";
+    out += title;
+    out.push_str("\n");
+    out + &line
+}
+
+struct Walk {
+    visited: HashSet<usize>,
+}
+
+impl Walk {
+    fn visit(&mut self, node: usize, parents: &HashMap<usize, usize>) -> bool {
+        // membership is a nil check, for maps' keys and sets alike
+        if self.visited.contains(&node) || !parents.contains_key(&node) {
+            return false;
+        }
+        self.visited.insert(node);
+        true
+    }
+}
+
+fn describe_level(name: &str) -> String {
+    let level = parse_level(name);
+    match level {
+        Ok(n) => format!("level {}", n),
+        Err(e) => e,
+    }
+}
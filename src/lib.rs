@@ -0,0 +1,8103 @@
+mod prelude;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::mem;
+
+use syntex_syntax as syntax;
+
+use syntax::ast;
+use syntax::ast::{Arg, BinOp, Block, Expr};
+use syntax::ast::{ExprKind, ItemKind, LitKind, PatKind, StmtKind, TyKind};
+use syntax::attr::HasAttrs;
+use syntax::codemap::{BytePos, FilePathMapping, Span};
+use syntax::parse::lexer::comments::{self, Comment, CommentStyle};
+use syntax::parse::token;
+use syntax::parse::ParseSess;
+use syntax::ptr::P;
+use syntax::symbol::keywords;
+use syntax::tokenstream::TokenTree;
+use syntax::visit::{self, Visitor};
+
+/// What to generate, the command line flags but `--check` and `--dump-ast`.
+#[derive(Default)]
+pub struct Options {
+    /// End fns returning () with `return nil`.
+    pub explicit_nil: bool,
+    /// Call main at the end, so the output can be run directly.
+    pub call_main: bool,
+    /// Keep #[test] fns and run them at the end, like cargo test.
+    pub emit_tests: bool,
+    /// Return a table of the pub items, for require.
+    pub lua_module: bool,
+    /// Include items gated behind #[cfg(name)] or #[cfg(feature = "name")].
+    pub cfgs: HashSet<String>,
+    /// Lua to start the output with, after the shebang.
+    pub header: String,
+}
+
+/// The rust in `src` as lua, `file` is its name in messages. Panics on
+/// anything unsupported, `check` lists them instead.
+pub fn transpile(file: &str, src: &str, options: &Options) -> String {
+    generate(file, src, options, false, |generator| generator.output())
+}
+
+/// Everything in `src` rua can't transpile yet, as `file:line:col: what`.
+pub fn check(file: &str, src: &str, options: &Options) -> Vec<String> {
+    generate(file, src, options, true, |generator| {
+        let codemap = generator.sess.codemap();
+        let unsupported = generator.unsupported.iter();
+        unsupported
+            .map(|(span, msg)| format!("{}: {}", codemap.span_to_string(*span), msg))
+            .collect()
+    })
+}
+
+/// What the parser made of `src`, for bug reports.
+pub fn dump_ast(file: &str, src: &str) -> String {
+    let (src, _) = old_syntax(src);
+    let sess = ParseSess::new(FilePathMapping::empty());
+    let krate = parse(file, src, &sess);
+    format!("{:#?}", krate)
+}
+
+fn parse(file: &str, src: String, sess: &ParseSess) -> ast::Crate {
+    match syntax::parse::parse_crate_from_source_str(file.to_string(), src, sess) {
+        Ok(_) if sess.span_diagnostic.has_errors() => panic!("errors but recovered"),
+        Ok(krate) => krate,
+        Err(_e) => panic!("errors while parsing"),
+    }
+}
+
+/// Runs a generator with `options` over `src`, then `f` over what it made.
+fn generate<R>(
+    file: &str,
+    src: &str,
+    options: &Options,
+    lenient: bool,
+    f: impl FnOnce(&Generator) -> R,
+) -> R {
+    let (src, labeled_blocks) = old_syntax(src);
+    let sess = ParseSess::new(FilePathMapping::empty());
+    let krate = parse(file, src.clone(), &sess);
+
+    // the parser drops comments, they're lexed again on the side
+    let (comments, _) =
+        comments::gather_comments_and_literals(&sess, file.to_string(), &mut src.as_bytes());
+
+    let mut generator = Generator::new(&sess);
+    generator.comments = comments;
+    generator.lenient = lenient;
+    generator.explicit_nil = options.explicit_nil;
+    generator.call_main = options.call_main;
+    generator.emit_tests = options.emit_tests;
+    generator.lua_module = options.lua_module;
+    generator.cfgs = options.cfgs.clone();
+    generator.header = options.header.clone();
+    generator.labeled_blocks = labeled_blocks;
+    // the lua comes out about as long as the rust
+    generator.buf.reserve(src.len());
+    generator.module(&krate.module);
+    f(&generator)
+}
+
+/// Spells newer syntax the way our parser knows: `..=` as `...`, labeled
+/// blocks `'a: {` as `'a: loop {`, returning where those labels are, and
+/// `const { .. }` as the block, lua evaluates everything at runtime.
+/// Comments are lexed from the result too so their spans line up.
+fn old_syntax(src: &str) -> (String, HashSet<BytePos>) {
+    let mut labels = HashSet::new();
+    let src = src.as_bytes();
+    let mut out = Vec::with_capacity(src.len());
+    let mut i = 0;
+    while i < src.len() {
+        let rest = &src[i..];
+        // skip over everything `..=` could be in without being a range
+        let skip = if rest.starts_with(b"//") {
+            rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len())
+        } else if rest.starts_with(b"/*") {
+            rest.windows(2)
+                .position(|w| w == b"*/")
+                .map_or(rest.len(), |end| end + 2)
+        } else if rest.starts_with(b"r#") && rest.get(2).is_some_and(|&c| is_ident_byte(c)) {
+            // r#const is a name, not a raw string or a const block
+            2 + rest[2..].iter().take_while(|&&c| is_ident_byte(c)).count()
+        } else if rest.starts_with(b"r\"") || rest.starts_with(b"r#") {
+            let hashes = rest[1..].iter().take_while(|&&c| c == b'#').count();
+            let mut end = vec![b'"'];
+            end.extend(std::iter::repeat_n(b'#', hashes));
+            let start = 2 + hashes;
+            rest.get(start..)
+                .and_then(|body| body.windows(end.len()).position(|w| w == &end[..]))
+                .map_or(rest.len(), |pos| start + pos + end.len())
+        } else if rest[0] == b'"' {
+            let mut j = 1;
+            while j < rest.len() && rest[j] != b'"' {
+                j += if rest[j] == b'\\' { 2 } else { 1 };
+            }
+            (j + 1).min(rest.len())
+        } else if rest.starts_with(b"'\"'") || rest.starts_with(b"'\\\"'") {
+            // '"' would look like the start of a string
+            rest.iter().skip(1).position(|&c| c == b'\'').unwrap() + 2
+        } else if rest.starts_with(b"..=") {
+            out.extend_from_slice(b"...");
+            i += 3;
+            continue;
+        } else if let Some(brace) = block_label(rest) {
+            labels.insert(BytePos(out.len() as u32));
+            out.extend_from_slice(&rest[..brace]);
+            out.extend_from_slice(b"loop ");
+            i += brace;
+            continue;
+        } else if is_const_block(rest, out.last()) {
+            out.extend_from_slice(b"     ");
+            i += "const".len();
+            continue;
+        } else {
+            1
+        };
+        out.extend_from_slice(&rest[..skip]);
+        i += skip;
+    }
+    (String::from_utf8(out).unwrap(), labels)
+}
+
+fn is_ident_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Whether `src` starts with `const {`, and isn't the end of a longer
+/// name after `prev`.
+fn is_const_block(src: &[u8], prev: Option<&u8>) -> bool {
+    src.starts_with(b"const")
+        && !prev.is_some_and(|&c| is_ident_byte(c))
+        && src[5..].iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{')
+}
+
+/// Where the `{` is if `src` starts with the label of a block, `'a: {`.
+fn block_label(src: &[u8]) -> Option<usize> {
+    let name = src.get(1..)?;
+    let len = name
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+        .count();
+    if src[0] != b'\'' || len == 0 || name.get(len) != Some(&b':') {
+        return None;
+    }
+    let space = name[len + 1..]
+        .iter()
+        .take_while(|c| c.is_ascii_whitespace())
+        .count();
+    let brace = 1 + len + 1 + space;
+    (src.get(brace) == Some(&b'{')).then_some(brace)
+}
+
+struct Generator<'a> {
+    sess: &'a ParseSess,
+    buf: String,
+    curr_indent: usize,
+    // locals we know hold a String, so write! can append to them.
+    strings: HashSet<ast::Name>,
+    // locals we know hold a char, so casting them gives their code point.
+    chars: HashSet<ast::Name>,
+    // locals with an integer type we know, for arithmetic that wraps.
+    ints: HashMap<ast::Name, ast::Name>,
+    // locals we know hold an integer, even when we don't know its type.
+    integrals: HashSet<ast::Name>,
+    // locals holding a HashSet, a table with the elements as keys.
+    sets: HashSet<ast::Name>,
+    // struct fields holding a HashSet, by their name.
+    set_fields: HashSet<ast::Name>,
+    // the `parse` a let with an integer type holds the result of, which
+    // parses an integer without a turbofish.
+    integer_parse: Option<Span>,
+    // locals holding a slice or an array.
+    slices: HashSet<ast::Name>,
+    // locals holding a HashMap or BTreeMap, a table keyed by the keys.
+    maps: HashSet<ast::Name>,
+    // struct fields holding a map, by their name.
+    map_fields: HashSet<ast::Name>,
+    // locals holding a Result, the error is in `__rua_err_<name>` next to
+    // them since results are the value, or nil and the error.
+    results: HashSet<ast::Name>,
+    // enclosing loops in this function and their labels, true if the loop
+    // is used as a value.
+    loops: Vec<(Option<ast::Name>, bool)>,
+    // where the labels of labeled blocks are, the parser sees them as loops.
+    labeled_blocks: HashSet<BytePos>,
+    // the type the fn we're in returns, `?` returns nil from Options and
+    // `.into()` converts to it.
+    returns: Option<ast::Name>,
+    // types with a From impl, `.into()` calls its from.
+    froms: HashSet<ast::Name>,
+    // the locals `?` expressions were hoisted into, by where they are, and
+    // the values that run before them.
+    tries: HashMap<Span, String>,
+    // enclosing labeled blocks in this function, true if used as a value.
+    blocks: Vec<(ast::Name, bool)>,
+    in_iife: bool,
+    // the local that branches of `let x = if ..` assign their value to,
+    // instead of returning it.
+    assign_to: Option<String>,
+    // helpers from the prelude the generated code uses.
+    helpers: Vec<&'static str>,
+    // lua from --header and --prelude-file, put before everything else.
+    header: String,
+    // the source's shebang line, kept out of `buf` so --module can't put
+    // anything before it.
+    shebang: String,
+    // comments from the source not emitted yet, their positions are
+    // offsets into the file.
+    comments: Vec<Comment>,
+    next_comment: usize,
+    // lenient generators record unsupported constructs instead of panicking.
+    lenient: bool,
+    // names enabled with --cfg
+    cfgs: HashSet<String>,
+    // fns and methods renamed with `#[lua(name = "...")]`, by their rust name.
+    renames: HashMap<ast::Name, ast::Name>,
+    // fns and methods taking their last argument as lua varargs.
+    varargs: HashSet<ast::Name>,
+    // fns and methods whose trailing Option arguments can be left out.
+    optional: HashSet<ast::Name>,
+    // names standing in for other lua, like the bindings of a match arm
+    // inside its guard.
+    aliases: HashMap<ast::Name, String>,
+    // type of the impl we're in, what `Self` means.
+    impl_ty: Option<ast::Name>,
+    // enums in the module, their values are tables tagged with the variant.
+    enums: HashSet<ast::Name>,
+    // the fields of the structs in the module, `..base` copies the rest.
+    structs: HashMap<ast::Name, Vec<ast::Name>>,
+    // fns that return tuples, which lua gets as multiple values.
+    tuple_fns: HashSet<ast::Name>,
+    // fns that return a Result, the value or nil and the error.
+    result_fns: HashSet<ast::Name>,
+    // the same for the associated fns and methods of each type, by the
+    // type and their name, free fns don't make methods of theirs return
+    // tuples.
+    tuple_methods: HashSet<(ast::Name, ast::Name)>,
+    result_methods: HashSet<(ast::Name, ast::Name)>,
+    // traits in the module and the methods they provide a default for.
+    traits: HashMap<ast::Name, Vec<ast::Name>>,
+    // `Type.method = Trait.method` for the defaults impls don't override,
+    // they're assigned at the end when every trait's been defined.
+    inherited: Vec<String>,
+    // end fns without a return type with `return nil`, so they always
+    // return exactly one value like fns returning ().
+    explicit_nil: bool,
+    // call main at the end of the module, if there is one.
+    call_main: bool,
+    // keep #[test] fns and append a runner calling them.
+    emit_tests: bool,
+    // make the items locals and return a table of the pub ones.
+    lua_module: bool,
+    // top level items declared as locals at the start of the module.
+    declared: HashSet<ast::Name>,
+    // the #[test] fns emitted, by their path, and whether they should panic.
+    tests: Vec<(Vec<ast::Name>, bool)>,
+    // modules in the crate, tables of their items.
+    modules: HashSet<ast::Name>,
+    // the modules the items being emitted are in.
+    mod_path: Vec<ast::Name>,
+    unsupported: Vec<(Span, String)>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(sess: &'a ParseSess) -> Self {
+        Self {
+            sess,
+            buf: String::new(),
+            curr_indent: 0,
+            strings: HashSet::new(),
+            chars: HashSet::new(),
+            ints: HashMap::new(),
+            integrals: HashSet::new(),
+            sets: HashSet::new(),
+            set_fields: HashSet::new(),
+            integer_parse: None,
+            slices: HashSet::new(),
+            maps: HashSet::new(),
+            map_fields: HashSet::new(),
+            results: HashSet::new(),
+            loops: Vec::new(),
+            labeled_blocks: HashSet::new(),
+            returns: None,
+            froms: HashSet::new(),
+            tries: HashMap::new(),
+            blocks: Vec::new(),
+            in_iife: false,
+            assign_to: None,
+            helpers: Vec::new(),
+            header: String::new(),
+            shebang: String::new(),
+            comments: Vec::new(),
+            next_comment: 0,
+            lenient: false,
+            cfgs: HashSet::new(),
+            renames: HashMap::new(),
+            varargs: HashSet::new(),
+            optional: HashSet::new(),
+            aliases: HashMap::new(),
+            impl_ty: None,
+            enums: HashSet::new(),
+            structs: HashMap::new(),
+            tuple_fns: HashSet::new(),
+            result_fns: HashSet::new(),
+            tuple_methods: HashSet::new(),
+            result_methods: HashSet::new(),
+            traits: HashMap::new(),
+            inherited: Vec::new(),
+            explicit_nil: false,
+            call_main: false,
+            emit_tests: false,
+            lua_module: false,
+            declared: HashSet::new(),
+            tests: Vec::new(),
+            modules: HashSet::new(),
+            mod_path: Vec::new(),
+            unsupported: Vec::new(),
+        }
+    }
+
+    /// The generated lua, preceded by the header and the helpers it uses.
+    /// A shebang stays on the first line, where it has to be.
+    fn output(&self) -> String {
+        let len = self.helpers.iter().map(|h| h.len() + 1).sum::<usize>()
+            + self.shebang.len()
+            + self.header.len()
+            + self.buf.len();
+        let mut out = String::with_capacity(len);
+        out.push_str(&self.shebang);
+        out.push_str(&self.header);
+        for helper in &self.helpers {
+            out.push_str(helper);
+            out.push('\n');
+        }
+        out.push_str(&self.buf);
+        out
+    }
+
+    /// Calls every test in a pcall, reporting them like cargo test.
+    fn test_runner(&mut self) {
+        let tests: Vec<String> = mem::take(&mut self.tests)
+            .into_iter()
+            .map(|(path, should_panic)| {
+                // tests in modules are in the module's table
+                let (name, modules) = path.split_last().unwrap();
+                let mut lua: Vec<String> = modules.iter().map(|m| m.to_string()).collect();
+                lua.push(self.lua_name(*name).to_string());
+                let rust: Vec<String> = path.iter().map(|name| name.to_string()).collect();
+                format!(
+                    "  {{{}, {}, {}}},\n",
+                    lua_string(&rust.join("::")),
+                    lua.join("."),
+                    should_panic
+                )
+            })
+            .collect();
+        self.push_str("local __rua_tests = {\n");
+        for test in tests {
+            self.push_str(&test);
+        }
+        self.push_str("}\n");
+        self.push_str(prelude::TEST_RUNNER);
+    }
+
+    /// Emits the comments in the source before `pos` as their own lines at
+    /// the current indentation, and the blank lines if `blank_lines`.
+    fn comments_before(&mut self, pos: BytePos, blank_lines: bool) {
+        let pos = if pos.0 == u32::MAX {
+            pos
+        } else {
+            self.sess.codemap().lookup_byte_offset(pos).pos
+        };
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.pos >= pos {
+                break;
+            }
+            let comment = comment.clone();
+            self.next_comment += 1;
+            match comment.style {
+                _ if self.next_comment == 1
+                    && comment
+                        .lines
+                        .first()
+                        .is_some_and(|line| line.starts_with("#!")) =>
+                {
+                    self.shebang = format!("{}\n", comment.lines[0]);
+                }
+                // one blank line is enough, and blocks don't start with one
+                CommentStyle::BlankLine => {
+                    if blank_lines
+                        && !self.buf.is_empty()
+                        && !self.buf.ends_with("\n\n")
+                        && !opens_block(&self.buf)
+                    {
+                        self.push_str("\n");
+                    }
+                }
+                // the comment goes after the line it was on
+                CommentStyle::Trailing if self.buf.ends_with('\n') => {
+                    self.buf.pop();
+                    self.push_str(&format!(" {}\n", lua_comment(&comment.lines.join("\n"))));
+                }
+                _ => {
+                    for line in lua_comment(&comment.lines.join("\n")).lines() {
+                        self.indent();
+                        self.push_str(line);
+                        self.push_str("\n");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Marks a prelude helper as used so it gets emitted.
+    fn helper(&mut self, helper: &'static str) {
+        if !self.helpers.contains(&helper) {
+            self.helpers.push(helper);
+        }
+    }
+
+    /// Marks the helpers lua written in the generator calls as used.
+    fn helpers_in(&mut self, lua: &str) {
+        for (name, helper) in prelude::HELPERS {
+            // __rua_map_or isn't a use of __rua_map
+            let used = lua.match_indices(name).any(|(i, _)| {
+                !lua[i + name.len()..].starts_with(|c: char| c == '_' || c.is_alphanumeric())
+            });
+            if used {
+                self.helper(helper);
+            }
+        }
+    }
+
+    /// Reports something we can't transpile, lenient generators emit nil in
+    /// its place and carry on.
+    fn unsupported(&mut self, span: Span, what: &str) {
+        self.report(span, what);
+        self.push_str("nil");
+    }
+
+    fn report(&mut self, span: Span, what: &str) {
+        let snippet = self
+            .sess
+            .codemap()
+            .span_to_snippet(span)
+            .unwrap_or_default();
+        let snippet = snippet.lines().next().unwrap_or_default();
+        let msg = format!("unsupported {}: `{}`", what, snippet);
+        if !self.lenient {
+            panic!("{}: {}", self.sess.codemap().span_to_string(span), msg);
+        }
+        self.unsupported.push((span, msg));
+    }
+
+    fn indent(&mut self) {
+        for _ in 0..self.curr_indent {
+            self.buf.push_str("  ");
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.buf.push_str(s);
+    }
+
+    fn module(&mut self, module: &ast::Mod) {
+        // fns can be used before they're declared, so find renames first
+        self.scan(module);
+        // renamed fns can be called anything
+        for (span, ident) in unspellable(module) {
+            if !self.renames.contains_key(&ident.name) {
+                self.report(span, "identifier lua can't spell");
+            }
+        }
+        for (span, ident) in clobbered_globals(module) {
+            if !self.renames.contains_key(&ident.name) {
+                self.report(span, "name of a lua global the output uses");
+            }
+        }
+        // locals can't be used before they're declared, so declare them all
+        // first and assign them as the items come
+        let mut exports = Vec::new();
+        if self.lua_module {
+            let names = self.declarations(module, &mut exports);
+            self.push_str("local M = {}\n");
+            if !names.is_empty() {
+                self.push_str(&format!("local {}\n", names.join(", ")));
+            }
+            self.push_str("\n");
+        }
+        for item in &module.items {
+            self.comments_before(item.span.lo, true);
+            self.item(item);
+        }
+        self.comments_before(BytePos(u32::MAX), false);
+        for inherited in mem::take(&mut self.inherited) {
+            self.push_str(&inherited);
+            self.push_str("\n");
+        }
+
+        let has_main = module.items.iter().any(|item| {
+            matches!(item.node, ItemKind::Fn(..))
+                && is_named(&item.ident, "main")
+                && self.cfg(&item.attrs)
+        });
+        if self.call_main && has_main {
+            let main = self.lua_name(ast::Name::intern("main"));
+            self.push_str(&format!("{}()\n", main));
+        }
+        if !self.tests.is_empty() {
+            self.test_runner();
+        }
+        if self.lua_module {
+            for name in exports {
+                self.push_str(&format!("M.{} = {}\n", name, name));
+            }
+            self.push_str("return M\n");
+        }
+    }
+
+    /// Records what's needed about the items of `module` and the modules
+    /// in it before any of them is emitted.
+    fn scan(&mut self, module: &ast::Mod) {
+        for item in &module.items {
+            match &item.node {
+                _ if !self.cfg(&item.attrs) => {}
+                ItemKind::Mod(inner) => {
+                    self.modules.insert(item.ident.name);
+                    self.scan(inner);
+                }
+                ItemKind::Fn(decl, ..) => {
+                    if returns_tuple(decl) {
+                        self.tuple_fns.insert(item.ident.name);
+                    }
+                    if returns_result(decl) {
+                        self.result_fns.insert(item.ident.name);
+                    }
+                    self.declare(item.ident.name, &item.attrs)
+                }
+                ItemKind::Enum(..) => {
+                    self.enums.insert(item.ident.name);
+                }
+                ItemKind::Struct(data, _) => {
+                    for field in data.fields() {
+                        if is_set_ty(&field.ty) {
+                            self.set_fields.extend(field.ident.map(|ident| ident.name));
+                        }
+                        if is_map_ty(&field.ty) {
+                            self.map_fields.extend(field.ident.map(|ident| ident.name));
+                        }
+                    }
+                    let fields = data.fields().iter().filter_map(|field| field.ident);
+                    let names = fields.map(|ident| ident.name).collect();
+                    self.structs.insert(item.ident.name, names);
+                }
+                ItemKind::Impl(_, _, _, _, Some(trait_ref), ty, _)
+                    if trait_ref.path.segments.last().unwrap().identifier.name == "From" =>
+                {
+                    self.froms.extend(ty_name(ty));
+                }
+                ItemKind::Trait(.., items) => {
+                    let provided = items
+                        .iter()
+                        .filter(|item| self.cfg(&item.attrs))
+                        .filter(|item| matches!(item.node, ast::TraitItemKind::Method(_, Some(_))))
+                        .map(|item| item.ident.name)
+                        .collect();
+                    self.traits.insert(item.ident.name, provided);
+                    for item in items {
+                        if self.cfg(&item.attrs) {
+                            self.declare(item.ident.name, &item.attrs);
+                        }
+                    }
+                }
+                ItemKind::Impl(.., ty, items) => {
+                    for item in items {
+                        if let (ast::ImplItemKind::Method(sig, _), Some(ty)) =
+                            (&item.node, ty_name(ty))
+                        {
+                            if returns_tuple(&sig.decl) {
+                                self.tuple_methods.insert((ty, item.ident.name));
+                            }
+                            if returns_result(&sig.decl) {
+                                self.result_methods.insert((ty, item.ident.name));
+                            }
+                        }
+                        if self.cfg(&item.attrs) {
+                            self.declare(item.ident.name, &item.attrs);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Declares the items of `module` so they can be used before they're
+    /// assigned, giving the names to declare as locals. `exports` gets the
+    /// pub ones.
+    fn declarations(&mut self, module: &ast::Mod, exports: &mut Vec<ast::Name>) -> Vec<String> {
+        let mut names = Vec::new();
+        for item in &module.items {
+            let name = match &item.node {
+                _ if !self.cfg(&item.attrs) => continue,
+                ItemKind::Fn(..) if is_test(&item.attrs) && !self.emit_tests => continue,
+                ItemKind::Fn(..) => self.lua_name(item.ident.name),
+                ItemKind::Struct(..)
+                | ItemKind::Mod(..)
+                | ItemKind::Enum(..)
+                | ItemKind::Trait(..)
+                | ItemKind::Const(..)
+                | ItemKind::Static(..) => item.ident.name,
+                _ => continue,
+            };
+            self.declared.insert(name);
+            names.push(name.to_string());
+            if item.vis == ast::Visibility::Public {
+                exports.push(name);
+            }
+            if let ItemKind::Trait(.., items) = &item.node {
+                for item in items {
+                    if !self.cfg(&item.attrs) {
+                        continue;
+                    }
+                    if let Some(alias) = associated_alias(name, item) {
+                        self.declared.insert(alias);
+                        names.push(alias.to_string());
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    /// Whether `name` is a top level item declared at the start of the
+    /// module, items in blocks are still their own locals.
+    fn is_declared(&self, name: ast::Name) -> bool {
+        self.curr_indent == 0 && self.declared.contains(&name)
+    }
+
+    /// Whether the `#[cfg(...)]` attributes in `attrs` are satisfied.
+    fn cfg(&self, attrs: &[ast::Attribute]) -> bool {
+        attrs
+            .iter()
+            .filter(|attr| attr.check_name("cfg"))
+            .all(|attr| match attr.meta_item_list().as_deref() {
+                Some([pred]) => self.cfg_pred(pred),
+                _ => false,
+            })
+    }
+
+    fn cfg_pred(&self, pred: &ast::NestedMetaItem) -> bool {
+        let pred = match pred.meta_item() {
+            Some(pred) => pred,
+            None => return false,
+        };
+        let preds = pred.meta_item_list().unwrap_or_default();
+        match &*pred.name().as_str() {
+            "not" => matches!(preds, [pred] if !self.cfg_pred(pred)),
+            "all" => preds.iter().all(|pred| self.cfg_pred(pred)),
+            "any" => preds.iter().any(|pred| self.cfg_pred(pred)),
+            _ if pred.is_meta_item_list() => false,
+            "feature" => pred
+                .value_str()
+                .is_some_and(|name| self.cfgs.contains(&*name.as_str())),
+            name => match pred.value_str() {
+                Some(value) => self.cfgs.contains(&format!("{}={}", name, value)),
+                None => self.cfgs.contains(name),
+            },
+        }
+    }
+
+    /// Records the `#[lua(...)]` attributes of a fn or method that change
+    /// how it's called.
+    fn declare(&mut self, name: ast::Name, attrs: &[ast::Attribute]) {
+        let attrs = lua_attrs(attrs);
+        let lua_name = attrs
+            .iter()
+            .find(|attr| attr.check_name("name"))
+            .and_then(|attr| attr.value_str());
+        if let Some(lua_name) = lua_name {
+            self.renames.insert(name, lua_name);
+        }
+        if attrs.iter().any(|attr| attr.check_name("varargs")) {
+            self.varargs.insert(name);
+        }
+        if attrs.iter().any(|attr| attr.check_name("optional")) {
+            self.optional.insert(name);
+        }
+    }
+
+    /// The name a fn or method has in lua.
+    fn lua_name(&self, name: ast::Name) -> ast::Name {
+        *self.renames.get(&name).unwrap_or(&name)
+    }
+
+    fn literal(&mut self, lit: &ast::Lit) {
+        match lit.node {
+            // raw strings stay readable without escapes in long brackets
+            LitKind::Str(s, ast::StrStyle::Raw(_)) if s.as_str().contains(['\\', '\'']) => {
+                let s = s.as_str();
+                self.push_str(&long_string(&s).unwrap_or_else(|| lua_string(&s)))
+            }
+            LitKind::Str(s, _) => self.push_str(&lua_text(&s.as_str())),
+            LitKind::Int(n, _) => write!(self.buf, "{}", n).unwrap(),
+            LitKind::Float(n, _) | LitKind::FloatUnsuffixed(n) => self.push_str(&n.as_str()),
+            // lua has no chars, they're just strings
+            LitKind::Char(c) => self.push_str(&lua_string(&c.to_string())),
+            // bytes are numbers, so b'a' + 1 works like in rust
+            LitKind::Byte(b) => write!(self.buf, "{}", b).unwrap(),
+            LitKind::Bool(b) => self.push_str(if b { "true" } else { "false" }),
+            _ => self.unsupported(lit.span, "literal"),
+        }
+    }
+
+    fn ident(&mut self, ident: &ast::Ident) {
+        self.push_str(&ident.name.as_str());
+    }
+
+    /// Emits `exprs` separated by commas.
+    fn exprs(&mut self, exprs: &[P<Expr>]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            self.value(expr);
+            // while not on the last guy, print comma
+            if i + 1 != exprs.len() {
+                self.push_str(", ");
+            }
+        }
+    }
+
+    /// Emits call arguments, a tuple returned by the last one spreads into
+    /// the remaining parameters like lua does.
+    fn tuple(&mut self, args: &[P<Expr>]) {
+        self.push_str("(");
+        if let Some((last, args)) = args.split_last() {
+            self.exprs(args);
+            if !args.is_empty() {
+                self.push_str(", ");
+            }
+            self.expr(last);
+        }
+        self.push_str(")");
+    }
+
+    /// Emits `expr` as one value, tuples returned as multiple values are
+    /// packed into a table so they aren't truncated or spread.
+    fn value(&mut self, expr: &ast::Expr) {
+        if self.returns_tuple(expr) {
+            self.push_str("{");
+            self.expr(expr);
+            self.push_str("}")
+        } else {
+            self.expr(expr)
+        }
+    }
+
+    fn returns_tuple(&self, expr: &ast::Expr) -> bool {
+        self.calls_one_of(expr, &self.tuple_fns, &self.tuple_methods)
+    }
+
+    /// Whether `expr` calls one of the free `fns` or one of the `methods`
+    /// of a type. Methods on receivers of a type we don't know match the
+    /// methods of any type with their name.
+    fn calls_one_of(
+        &self,
+        expr: &ast::Expr,
+        fns: &HashSet<ast::Name>,
+        methods: &HashSet<(ast::Name, ast::Name)>,
+    ) -> bool {
+        let method = |ty: Option<ast::Name>, name: ast::Name| match ty {
+            Some(ty) => methods.contains(&(ty, name)),
+            None => methods.iter().any(|&(_, method)| method == name),
+        };
+        match &expr.node {
+            ExprKind::Call(func, _) => match &func.node {
+                ExprKind::Path(None, path) => match &path.segments[..] {
+                    [.., ty, name]
+                        if !self.modules.contains(&ty.identifier.name)
+                            && !["self", "super", "crate"]
+                                .iter()
+                                .any(|module| is_named(&ty.identifier, module)) =>
+                    {
+                        method(Some(self.type_name(&ty.identifier)), name.identifier.name)
+                    }
+                    segments => fns.contains(&segments.last().unwrap().identifier.name),
+                },
+                _ => false,
+            },
+            ExprKind::MethodCall(name, _, args) => {
+                let ty = match &args[0].node {
+                    ExprKind::Path(None, path) if path_name(path) == "self" => self.impl_ty,
+                    _ => None,
+                };
+                method(ty, name.node.name)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `expr` is a Result, so it's a value, or nil and the error.
+    fn is_result(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Paren(expr) => self.is_result(expr),
+            ExprKind::Call(func, _) if is_ok_or_err(func) => true,
+            ExprKind::Call(..) | ExprKind::MethodCall(..) => {
+                self.calls_one_of(expr, &self.result_fns, &self.result_methods)
+            }
+            ExprKind::Path(..) => self.result_local(expr).is_some(),
+            ExprKind::Block(block) => block_value(block).is_some_and(|tail| self.is_result(tail)),
+            ExprKind::If(_, block, els) | ExprKind::IfLet(_, _, block, els) => {
+                block_value(block).is_some_and(|tail| self.is_result(tail))
+                    || els.as_ref().is_some_and(|els| self.is_result(els))
+            }
+            ExprKind::Match(_, arms) => arms.iter().any(|arm| self.is_result(&arm.body)),
+            _ => false,
+        }
+    }
+
+    /// The local holding a Result `expr` names.
+    fn result_local(&self, expr: &ast::Expr) -> Option<ast::Name> {
+        match &expr.node {
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                let name = path.segments[0].identifier.name;
+                Some(name)
+                    .filter(|name| self.results.contains(name) && !self.aliases.contains_key(name))
+            }
+            _ => None,
+        }
+    }
+
+    /// Emits `expr`, both values of it if it's `Err(e)` or a local holding
+    /// a Result. Lua drops the error anywhere else, so only returns and lets
+    /// use this.
+    fn result_value(&mut self, expr: &ast::Expr) {
+        if let Some(err) = err_payload(expr) {
+            self.push_str("nil, ");
+            return self.expr(err);
+        }
+        self.expr(expr);
+        if let Some(name) = self.result_local(expr) {
+            self.push_str(&format!(", {}", result_err(name)));
+        }
+    }
+
+    /// Emits a table constructor with `exprs` as its array part.
+    fn table(&mut self, exprs: &[P<Expr>]) {
+        self.push_str("{");
+        self.exprs(exprs);
+        self.push_str("}");
+    }
+
+    fn args(&mut self, args: &[Arg]) {
+        self.push_str("(");
+        for (i, arg) in args.iter().enumerate() {
+            if let PatKind::Ident(_, ident, _) = &arg.pat.node {
+                self.track_char(ident.node.name, is_char_ty(&arg.ty));
+                self.track_int(ident.node.name, int_ty(&arg.ty));
+                track(
+                    &mut self.integrals,
+                    ident.node.name,
+                    int_ty(&arg.ty).is_some(),
+                );
+                track(&mut self.sets, ident.node.name, is_set_ty(&arg.ty));
+                track(&mut self.maps, ident.node.name, is_map_ty(&arg.ty));
+                track(&mut self.slices, ident.node.name, is_slice_ty(&arg.ty));
+                track(&mut self.strings, ident.node.name, is_string_ty(&arg.ty));
+            }
+            self.pat(&arg.pat);
+            // while not on the last guy, print comma
+            if i + 1 != args.len() {
+                self.push_str(", ");
+            }
+        }
+        self.push_str(")");
+    }
+
+    fn block(&mut self, block: &P<Block>) {
+        self.stmts(block, false);
+    }
+
+    /// Like `block`, but the value of the trailing expression is returned.
+    fn return_block(&mut self, block: &P<Block>) {
+        self.stmts(block, true);
+    }
+
+    fn stmts(&mut self, block: &P<Block>, ret: bool) {
+        self.curr_indent += 1;
+        let stmts: Vec<_> = block
+            .stmts
+            .iter()
+            .filter(|stmt| self.cfg(stmt.attrs()))
+            .collect();
+        // fns in a block are locals to it, declared up front since they can
+        // call each other before they're defined
+        let fns: Vec<String> = stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.node {
+                StmtKind::Item(item)
+                    if matches!(item.node, ItemKind::Fn(..))
+                        && self.cfg(&item.attrs)
+                        && !is_test(&item.attrs) =>
+                {
+                    Some(self.lua_name(item.ident.name).to_string())
+                }
+                _ => None,
+            })
+            .collect();
+        if !fns.is_empty() {
+            self.comments_before(stmts[0].span.lo, true);
+            self.lines(&format!("local {}", fns.join(", ")));
+        }
+        for (i, stmt) in stmts.iter().enumerate() {
+            let last = i + 1 == stmts.len();
+            self.comments_before(stmt.span.lo, true);
+            match &stmt.node {
+                StmtKind::Expr(expr) if last && ret => {
+                    self.hoist_tries(expr);
+                    self.indent();
+                    self.ret(expr);
+                    self.push_str("\n");
+                }
+                // leave out `{}`, it would just be an empty `do end`
+                _ if is_empty_block(stmt) && !(last && ret) => {}
+                // values nothing uses, like the tail of a loop body
+                StmtKind::Expr(expr) | StmtKind::Semi(expr) if is_pure(expr) && !(last && ret) => {}
+                StmtKind::Item(item) if is_std_use(item) => {}
+                _ if !last && is_return(stmt) => {
+                    // lua only allows return at the end of a block, so give
+                    // early returns a block of their own.
+                    self.indent();
+                    self.push_str("do ");
+                    self.stmt_expr(stmt);
+                    self.push_str(" end\n");
+                }
+                _ => self.stmt(stmt),
+            }
+        }
+        // comments at the end of the block, blank lines there would just
+        // be before the end
+        self.comments_before(block.span.hi, false);
+        self.curr_indent -= 1;
+    }
+
+    /// Returns the value of `expr`, pushing the return into the branches of
+    /// ifs, blocks and loops since lua can't use them as values.
+    fn ret(&mut self, expr: &ast::Expr) {
+        match &expr.node {
+            ExprKind::If(..) | ExprKind::IfLet(..) => self.if_(expr, true),
+            ExprKind::Match(value, arms) => self.match_(value, arms, true),
+            // `break 'a value` returns the value of the block
+            ExprKind::Loop(block, Some(label))
+                if self.assign_to.is_none() && self.labeled_blocks.contains(&label.span.lo) =>
+            {
+                self.blocks.push((label.node.name, true));
+                self.push_str("do\n");
+                self.return_block(block);
+                self.end();
+                self.blocks.pop();
+            }
+            ExprKind::Loop(block, label) if self.assign_to.is_none() => {
+                self.loop_(block, true, label.map(|label| label.node.name))
+            }
+            ExprKind::Block(block) => {
+                self.push_str("do\n");
+                self.return_block(block);
+                self.end()
+            }
+            ExprKind::ForLoop(..)
+            | ExprKind::While(..)
+            | ExprKind::WhileLet(..)
+            | ExprKind::Ret(_)
+            | ExprKind::Break(..)
+            | ExprKind::Continue(_)
+            | ExprKind::Assign(..)
+            | ExprKind::AssignOp(..) => self.expr_stmt(expr),
+            _ => match &self.assign_to {
+                Some(target) => {
+                    self.push_str(&format!("{} = ", target));
+                    self.result_value(expr)
+                }
+                None => self.return_value(expr),
+            },
+        }
+    }
+
+    /// Emits `local name`, unless it's declared already, and an if chain
+    /// assigning it the value of `init`, since lua can't use ifs as values.
+    /// Returns in the branches still return from the function.
+    fn local_branches(&mut self, name: String, init: &ast::Expr, declare: bool) {
+        if declare {
+            self.push_str(&format!("local {}\n", name));
+            self.indent();
+        }
+        let outer = self.assign_to.replace(name);
+        self.ret(init);
+        self.assign_to = outer;
+    }
+
+    /// Returns `expr` from the function, tuples are returned as multiple
+    /// values. IIFEs return tuples as they are since they're used as a
+    /// single value.
+    fn return_value(&mut self, expr: &ast::Expr) {
+        // returning () is returning nothing
+        if is_unit(expr) && !self.in_iife {
+            return self.push_str(if self.explicit_nil {
+                "return nil"
+            } else {
+                "return"
+            });
+        }
+        self.push_str("return ");
+        match &expr.node {
+            ExprKind::Tup(exprs) if !exprs.is_empty() && !self.in_iife => self.exprs(exprs),
+            _ if self.result_local(expr).is_some() || err_payload(expr).is_some() => {
+                self.result_value(expr)
+            }
+            _ => self.converted(expr, self.returns),
+        }
+    }
+
+    /// Runs `f` for the body of a new lua function, which can't break out
+    /// of the loops around it.
+    fn function<F: FnOnce(&mut Self)>(&mut self, iife: bool, f: F) {
+        let loops = mem::take(&mut self.loops);
+        let blocks = mem::take(&mut self.blocks);
+        let returns = self.returns.take();
+        let tries = mem::take(&mut self.tries);
+        let in_iife = mem::replace(&mut self.in_iife, iife);
+        let assign_to = self.assign_to.take();
+        f(self);
+        self.loops = loops;
+        self.blocks = blocks;
+        self.returns = returns;
+        self.tries = tries;
+        self.in_iife = in_iife;
+        self.assign_to = assign_to;
+    }
+
+    /// Wraps `expr` in a function that's called immediately, so ifs, loops
+    /// and blocks can be used as values.
+    fn iife(&mut self, expr: &ast::Expr) {
+        self.push_str("(function()\n");
+        self.function(true, |g| match &expr.node {
+            ExprKind::Block(block) => g.return_block(block),
+            ExprKind::Loop(block, Some(label)) if g.labeled_blocks.contains(&label.span.lo) => {
+                g.blocks.push((label.node.name, true));
+                g.return_block(block);
+                g.blocks.pop();
+            }
+            _ => {
+                g.curr_indent += 1;
+                g.indent();
+                g.ret(expr);
+                g.push_str("\n");
+                g.curr_indent -= 1;
+            }
+        });
+        self.indent();
+        self.push_str("end)()");
+    }
+
+    /// Emits an if chain, `ret` returns the value of each branch.
+    fn if_(&mut self, expr: &ast::Expr, ret: bool) {
+        // if let on anything but a place keeps the value in a local, so
+        // it's only evaluated once
+        let scoped = match &expr.node {
+            ExprKind::IfLet(pat, value, ..)
+                if !is_place(value)
+                    || result_pat(pat).is_some() && self.result_local(value).is_none() =>
+            {
+                self.push_str("do\n");
+                self.curr_indent += 1;
+                self.indent();
+                // results are the value, or nil and the error
+                if result_pat(pat).is_some() {
+                    if is_place(value) {
+                        self.report(
+                            value.span,
+                            "Ok or Err pattern on a place that isn't a local holding a Result",
+                        );
+                    }
+                    self.push_str("local __rua_v, __rua_err = ");
+                } else {
+                    self.push_str("local __rua_v = ");
+                }
+                self.result_value(value);
+                self.push_str("\n");
+                self.indent();
+                true
+            }
+            _ => false,
+        };
+
+        let mut next = Some(expr);
+        let mut first = true;
+        while let Some(expr) = next.take() {
+            let keyword = if first { "if " } else { "elseif " };
+            match &expr.node {
+                ExprKind::If(cond, block, els) => {
+                    self.push_str(keyword);
+                    self.expr(cond);
+                    self.push_str(" then");
+                    next = non_empty(els);
+                    // guard clauses fit on one line
+                    if first
+                        && next.is_none()
+                        && block.stmts.len() == 1
+                        && diverges(&block.stmts[0])
+                    {
+                        let body = self.capture(|g| g.stmts(block, ret));
+                        if body.trim().lines().count() == 1 {
+                            self.push_str(&format!(" {} end", body.trim()));
+                            return;
+                        }
+                        self.push_str("\n");
+                        self.push_str(&body);
+                    } else {
+                        self.push_str("\n");
+                        self.stmts(block, ret);
+                    }
+                }
+                ExprKind::IfLet(pat, value, block, els)
+                    if first
+                        || is_place(value)
+                            && (result_pat(pat).is_none()
+                                || self.result_local(value).is_some()) =>
+                {
+                    let result = self.result_local(value);
+                    let (pat, value, test) = match (result_pat(pat), result) {
+                        (Some(_), Some(result)) => {
+                            let value = self.capture(|g| g.expr(value));
+                            result_arm(pat, &value, Some(&result_err(result)))
+                        }
+                        (Some(_), None) => result_arm(pat, "__rua_v", Some("__rua_err")),
+                        (None, _) if is_place(value) => {
+                            (&**pat, self.capture(|g| g.expr(value)), String::new())
+                        }
+                        (None, _) => (&**pat, "__rua_v".to_string(), String::new()),
+                    };
+                    self.push_str(keyword);
+                    let mut tests = vec![test];
+                    self.pat_tests(pat, &value, &mut tests);
+                    tests.retain(|test| !test.is_empty());
+                    if tests.is_empty() {
+                        self.push_str("true");
+                    } else {
+                        self.push_str(&tests.join(" and "));
+                    }
+                    self.push_str(" then\n");
+                    self.curr_indent += 1;
+                    self.pat_binds(pat, &value);
+                    self.curr_indent -= 1;
+                    self.stmts(block, ret);
+                    next = non_empty(els);
+                }
+                ExprKind::Block(block) if !first => {
+                    self.push_str("else\n");
+                    self.stmts(block, ret);
+                }
+                ExprKind::IfLet(..) => {
+                    // needs a scope of its own for its value
+                    self.push_str("else\n");
+                    self.curr_indent += 1;
+                    self.indent();
+                    self.if_(expr, ret);
+                    self.push_str("\n");
+                    self.curr_indent -= 1;
+                }
+                _ => self.unsupported(expr.span, "else"),
+            }
+            first = false;
+            if next.is_some() {
+                self.indent();
+            }
+        }
+        self.end();
+
+        if scoped {
+            self.push_str("\n");
+            self.curr_indent -= 1;
+            self.end();
+        }
+    }
+
+    /// Emits a match as an if chain, `ret` returns the value of each arm.
+    fn match_(&mut self, value: &ast::Expr, arms: &[ast::Arm], ret: bool) {
+        // a single arm that always matches is just a let
+        if let [arm] = arms {
+            if arm.pats.len() == 1
+                && arm.guard.is_none()
+                && self.arm_test(&arm.pats, None, "__rua_v", None).0 == "true"
+            {
+                let block = let_block(&arm.pats[0], value, &arm.body);
+                return if ret {
+                    self.ret(&block)
+                } else {
+                    self.expr_stmt(&block)
+                };
+            }
+        }
+        // results are the value, or nil and the error
+        let results = arms
+            .iter()
+            .any(|arm| arm.pats.iter().any(|pat| result_pat(pat).is_some()));
+        let result = self.result_local(value).filter(|_| results);
+        let scoped = !is_place(value) || results && result.is_none();
+        let (value, err) = if scoped {
+            self.push_str("do\n");
+            self.curr_indent += 1;
+            self.indent();
+            if results {
+                if is_place(value) {
+                    self.report(
+                        value.span,
+                        "Ok or Err pattern on a place that isn't a local holding a Result",
+                    );
+                }
+                self.push_str("local __rua_v, __rua_err = ");
+            } else {
+                self.push_str("local __rua_v = ");
+            }
+            self.result_value(value);
+            self.push_str("\n");
+            self.indent();
+            let err = Some("__rua_err".to_string()).filter(|_| results);
+            ("__rua_v".to_string(), err)
+        } else {
+            (self.capture(|g| g.expr(value)), result.map(result_err))
+        };
+
+        let mut open = false;
+        for (i, arm) in arms.iter().enumerate() {
+            let guard = arm.guard.as_deref();
+            let (test, binds) = self.arm_test(&arm.pats, guard, &value, err.as_deref());
+
+            // matches are exhaustive, so the last arm always matches
+            let last = i + 1 == arms.len() || (arm.guard.is_none() && test == "true");
+            let empty = match &arm.body.node {
+                ExprKind::Block(block) => block.stmts.is_empty(),
+                ExprKind::Tup(exprs) => exprs.is_empty(),
+                _ => false,
+            };
+            if last && empty && !ret {
+                break;
+            }
+            if open {
+                self.indent();
+            }
+            match (open, last) {
+                (false, true) => self.push_str("do\n"),
+                (false, false) => self.push_str(&format!("if {} then\n", test)),
+                (true, true) => self.push_str("else\n"),
+                (true, false) => self.push_str(&format!("elseif {} then\n", test)),
+            }
+            open = true;
+
+            self.curr_indent += 1;
+            for (name, value) in &binds {
+                self.indent();
+                self.push_str(&format!("local {} = {}\n", name, value));
+            }
+            self.curr_indent -= 1;
+            match &arm.body.node {
+                ExprKind::Block(block) => self.stmts(block, ret),
+                // `_ => ()` does nothing
+                ExprKind::Tup(exprs) if exprs.is_empty() && !ret => {}
+                _ => {
+                    self.curr_indent += 1;
+                    self.indent();
+                    if ret {
+                        self.ret(&arm.body);
+                    } else {
+                        self.expr_stmt(&arm.body);
+                    }
+                    self.push_str("\n");
+                    self.curr_indent -= 1;
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        if open {
+            self.end();
+        }
+
+        if scoped {
+            self.push_str("\n");
+            self.curr_indent -= 1;
+            self.end();
+        }
+    }
+
+    /// The lua condition for a match arm with patterns `pats` and `guard`
+    /// matching `value`, and the names the arm binds. `err` is the error
+    /// of a Result value, which Ok and Err patterns test.
+    fn arm_test(
+        &mut self,
+        pats: &[P<ast::Pat>],
+        guard: Option<&ast::Expr>,
+        value: &str,
+        err: Option<&str>,
+    ) -> (String, Vec<(ast::Name, String)>) {
+        let mut binds = Vec::new();
+        let (pat, matched, _) = result_arm(&pats[0], value, err);
+        self.pat_bindings(pat, &matched, &mut binds);
+        // `x @ 1 | x @ 2` is fine, alternatives binding different
+        // values would need to know which one matched
+        for pat in &pats[1..] {
+            let mut other = Vec::new();
+            let (inner, matched, _) = result_arm(pat, value, err);
+            self.pat_bindings(inner, &matched, &mut other);
+            if other != binds {
+                self.report(pat.span, "different bindings in | patterns");
+            }
+        }
+        let mut tests: Vec<String> = pats
+            .iter()
+            .map(|pat| {
+                let (pat, matched, test) = result_arm(pat, value, err);
+                match self.pat_test(pat, &matched) {
+                    rest if test.is_empty() => rest,
+                    rest if rest == "true" => test,
+                    rest => format!("{} and {}", test, rest),
+                }
+            })
+            .collect();
+        if tests.len() > 1 {
+            let alternatives: Vec<String> = tests
+                .iter()
+                .map(|test| {
+                    if test.contains(" and ") {
+                        format!("({})", test)
+                    } else {
+                        test.clone()
+                    }
+                })
+                .collect();
+            let either = alternatives.join(" or ");
+            // the guard applies to all of them
+            tests = vec![if guard.is_some() {
+                format!("({})", either)
+            } else {
+                either
+            }];
+        }
+        if let Some(guard) = guard {
+            // the guard runs before the arm binds anything, so it uses
+            // what the names are bound to instead
+            let outer = mem::replace(&mut self.aliases, binds.iter().cloned().collect());
+            let guard =
+                self.capture(|g| g.operand(guard, |p| p <= precedence(ast::BinOpKind::And)));
+            self.aliases = outer;
+            tests.retain(|test| test != "true");
+            tests.push(guard);
+        }
+        (tests.join(" and "), binds)
+    }
+
+    /// The lua condition for `pat` matching `value`.
+    fn pat_test(&mut self, pat: &ast::Pat, value: &str) -> String {
+        let mut tests = Vec::new();
+        self.pat_tests(pat, value, &mut tests);
+        if tests.is_empty() {
+            "true".to_string()
+        } else {
+            tests.join(" and ")
+        }
+    }
+
+    fn pat_tests(&mut self, pat: &ast::Pat, value: &str, tests: &mut Vec<String>) {
+        match &pat.node {
+            PatKind::Wild => {}
+            // None parses as a binding
+            PatKind::Ident(_, ident, None) if is_named(&ident.node, "None") => {
+                tests.push(format!("{} == nil", value))
+            }
+            PatKind::Ident(_, _, None) => {}
+            PatKind::Ident(_, _, Some(pat)) => self.pat_tests(pat, value, tests),
+            PatKind::Path(None, path) if path_name(path) == "None" => {
+                tests.push(format!("{} == nil", value))
+            }
+            // Some(x) is just x
+            PatKind::TupleStruct(path, pats, None)
+                if path_name(path) == "Some" && pats.len() == 1 =>
+            {
+                tests.push(format!("{} ~= nil", value));
+                self.pat_tests(&pats[0], value, tests);
+            }
+            PatKind::Path(None, path) if constant(path).is_some() => {
+                tests.push(format!("{} == {}", value, constant(path).unwrap()))
+            }
+            PatKind::Path(None, path) if self.variant(path).is_some() => {
+                let variant = self.variant(path).unwrap();
+                tests.push(format!(
+                    "{}.tag == {}",
+                    value,
+                    lua_string(&variant.as_str())
+                ));
+            }
+            PatKind::TupleStruct(path, pats, rest) if self.variant(path).is_some() => {
+                let variant = self.variant(path).unwrap();
+                tests.push(format!(
+                    "{}.tag == {}",
+                    value,
+                    lua_string(&variant.as_str())
+                ));
+                for (pat, elem) in pats.iter().zip(elems(value, pats.len(), *rest)) {
+                    self.pat_tests(pat, &elem, tests);
+                }
+            }
+            PatKind::Tuple(pats, rest) => {
+                for (pat, elem) in pats.iter().zip(elems(value, pats.len(), *rest)) {
+                    self.pat_tests(pat, &elem, tests);
+                }
+            }
+            PatKind::Struct(path, fields, _) => {
+                if let Some(variant) = self.variant(path) {
+                    tests.push(format!(
+                        "{}.tag == {}",
+                        value,
+                        lua_string(&variant.as_str())
+                    ));
+                }
+                for field in fields {
+                    let value = format!("{}.{}", value, field.node.ident.name);
+                    self.pat_tests(&field.node.pat, &value, tests);
+                }
+            }
+            PatKind::Slice(before, rest, after) => {
+                let len = before.len() + after.len();
+                if rest.is_some() {
+                    tests.push(format!("#{} >= {}", value, len));
+                } else {
+                    tests.push(format!("#{} == {}", value, len));
+                }
+                let pats = before.iter().chain(after);
+                for (pat, elem) in pats.zip(elems(value, len, Some(before.len()))) {
+                    self.pat_tests(pat, &elem, tests);
+                }
+            }
+            PatKind::Lit(expr) => {
+                let lit = self.capture(|g| g.expr(expr));
+                tests.push(format!("{} == {}", value, lit));
+            }
+            PatKind::Range(lo, hi, end) => {
+                let lo = self.capture(|g| g.expr(lo));
+                let hi = self.capture(|g| g.expr(hi));
+                tests.push(format!("{} >= {}", value, lo));
+                match end {
+                    ast::RangeEnd::Included => tests.push(format!("{} <= {}", value, hi)),
+                    ast::RangeEnd::Excluded => tests.push(format!("{} < {}", value, hi)),
+                }
+            }
+            PatKind::Ref(pat, _) => self.pat_tests(pat, value, tests),
+            _ => {
+                self.report(pat.span, "pattern");
+                tests.push("false".to_string());
+            }
+        }
+    }
+
+    /// Emits locals for the names `pat` binds out of `value`.
+    fn pat_binds(&mut self, pat: &ast::Pat, value: &str) {
+        let mut binds = Vec::new();
+        self.pat_bindings(pat, value, &mut binds);
+        for (name, value) in binds {
+            self.indent();
+            self.push_str(&format!("local {} = {}\n", name, value));
+        }
+    }
+
+    /// The names `pat` binds and the lua for what they're bound to.
+    fn pat_bindings(&mut self, pat: &ast::Pat, value: &str, binds: &mut Vec<(ast::Name, String)>) {
+        match &pat.node {
+            PatKind::Ident(_, ident, _) if is_named(&ident.node, "None") => {}
+            PatKind::Ident(_, ident, sub) => {
+                binds.push((ident.node.name, value.to_string()));
+                if let Some(sub) = sub {
+                    self.pat_bindings(sub, value, binds);
+                }
+            }
+            PatKind::TupleStruct(path, pats, None) if path_name(path) == "Some" => {
+                self.pat_bindings(&pats[0], value, binds)
+            }
+            PatKind::Tuple(pats, rest) | PatKind::TupleStruct(_, pats, rest) => {
+                for (pat, elem) in pats.iter().zip(elems(value, pats.len(), *rest)) {
+                    self.pat_bindings(pat, &elem, binds);
+                }
+            }
+            PatKind::Struct(_, fields, _) => {
+                for field in fields {
+                    let value = format!("{}.{}", value, field.node.ident.name);
+                    self.pat_bindings(&field.node.pat, &value, binds);
+                }
+            }
+            PatKind::Slice(before, rest, after) => {
+                let len = before.len() + after.len();
+                let pats = before.iter().chain(after);
+                for (pat, elem) in pats.zip(elems(value, len, Some(before.len()))) {
+                    self.pat_bindings(pat, &elem, binds);
+                }
+                // `rest..` gets a table of the middle
+                if let Some(rest) = rest {
+                    let end = match after.len() {
+                        0 => format!("#{}", value),
+                        n => format!("#{} - {}", value, n),
+                    };
+                    let middle =
+                        format!("{{table.unpack({}, {}, {})}}", value, before.len() + 1, end);
+                    self.pat_bindings(rest, &middle, binds);
+                }
+            }
+            PatKind::Ref(pat, _) => self.pat_bindings(pat, value, binds),
+            _ => {}
+        }
+    }
+
+    /// Runs `f` against an empty buffer, returning what it emitted.
+    fn capture<F: FnOnce(&mut Self)>(&mut self, f: F) -> String {
+        let buf = mem::take(&mut self.buf);
+        f(self);
+        mem::replace(&mut self.buf, buf)
+    }
+
+    fn for_(
+        &mut self,
+        pat: &ast::Pat,
+        iter: &ast::Expr,
+        block: &P<Block>,
+        label: Option<ast::Name>,
+    ) {
+        if let PatKind::Ident(_, ident, _) = &pat.node {
+            self.track_char(ident.node.name, chars_of(iter).is_some());
+        }
+        if let Some((a, b)) = zipped(iter) {
+            // zip stops at the end of the shorter one
+            let a = self.capture(|g| g.expr(a));
+            let b = self.capture(|g| g.expr(b));
+            self.push_str(&format!("for __rua_i = 1, math.min(#{}, #{}) do\n", a, b));
+            let (x, y) = (format!("{}[__rua_i]", a), format!("{}[__rua_i]", b));
+            self.curr_indent += 1;
+            match &pat.node {
+                PatKind::Tuple(pats, None) if pats.len() == 2 => {
+                    self.pat_binds(&pats[0], &x);
+                    self.pat_binds(&pats[1], &y);
+                }
+                _ => self.pat_binds(pat, &format!("{{{}, {}}}", x, y)),
+            }
+            self.curr_indent -= 1;
+        } else if matches!(iter.node, ExprKind::MethodCall(method, ..) if is_named(&method.node, "zip"))
+        {
+            self.unsupported(iter.span, "zip of vectors that aren't locals")
+        } else if matches!(pat.node, PatKind::Ident(_, _, None) | PatKind::Wild) {
+            let var = self.capture(|g| g.pat(pat));
+            self.for_head(&var, iter);
+        } else {
+            // destructure each element at the top of the body
+            self.for_head("__rua_x", iter);
+            self.curr_indent += 1;
+            self.pat_binds(pat, "__rua_x");
+            self.curr_indent -= 1;
+        }
+        self.loop_body(block, false, label);
+        self.end();
+        self.loop_label(block, label)
+    }
+
+    /// Emits `for var in iter do`, the loop over what rust iterates.
+    fn for_head(&mut self, var: &str, iter: &ast::Expr) {
+        let iter = match &iter.node {
+            ExprKind::Paren(iter) => iter,
+            _ => iter,
+        };
+        self.push_str("for ");
+        match &iter.node {
+            ExprKind::Range(Some(start), Some(end), ast::RangeLimits::HalfOpen) => {
+                self.push_str(var);
+                self.push_str(" = ");
+                self.expr(start);
+                self.push_str(", ");
+                self.minus_one(end);
+            }
+            ExprKind::Range(Some(start), Some(end), ast::RangeLimits::Closed) => {
+                self.push_str(var);
+                self.push_str(" = ");
+                self.expr(start);
+                self.push_str(", ");
+                self.expr(end);
+            }
+            // (a..b).rev() counts down from the last number with a -1 step
+            ExprKind::MethodCall(method, _, args)
+                if is_named(&method.node, "rev") && bounded_range(&args[0]).is_some() =>
+            {
+                let (start, end, limits) = bounded_range(&args[0]).unwrap();
+                self.push_str(var);
+                self.push_str(" = ");
+                match limits {
+                    ast::RangeLimits::HalfOpen => self.minus_one(end),
+                    ast::RangeLimits::Closed => self.expr(end),
+                }
+                self.push_str(", ");
+                self.expr(start);
+                self.push_str(", -1");
+            }
+            _ if chars_of(iter).is_some() => {
+                let (s, rev) = chars_of(iter).unwrap();
+                if rev {
+                    self.helper(prelude::REV_CHARS);
+                    self.push_str("_, ");
+                    self.push_str(var);
+                    self.push_str(" in ipairs(__rua_rev_chars(");
+                    self.expr(s);
+                    self.push_str("))");
+                } else {
+                    self.push_str(var);
+                    self.push_str(" in ");
+                    self.receiver(s);
+                    self.push_str(":gmatch(utf8.charpattern)");
+                }
+            }
+            // v.iter().rev() counts the indices down and takes the element
+            ExprKind::MethodCall(method, _, args)
+                if is_named(&method.node, "rev") && is_place(iterable(&args[0])) =>
+            {
+                let v = self.capture(|g| g.expr(iterable(&args[0])));
+                self.push_str(&format!("__rua_i = #{}, 1, -1 do\n", v));
+                self.curr_indent += 1;
+                self.lines(&format!("local {} = {}[__rua_i]", var, v));
+                self.curr_indent -= 1;
+                return;
+            }
+            ExprKind::MethodCall(method, _, args) if is_named(&method.node, "rev") => {
+                self.unsupported(args[0].span, "rev of a vector that isn't a local")
+            }
+            // sets keep their elements in the keys
+            _ if self.is_set(iterable(iter)) => {
+                self.push_str(var);
+                self.push_str(" in pairs(");
+                self.expr(iterable(iter));
+                self.push_str(")");
+            }
+            _ => {
+                self.push_str("_, ");
+                self.push_str(var);
+                self.push_str(" in ipairs(");
+                self.expr(iterable(iter));
+                self.push_str(")");
+            }
+        }
+        self.push_str(" do\n");
+    }
+
+    /// Lowers an iterator chain ending in `terminal` to a loop in a function
+    /// that returns as soon as it knows the answer. `iter` is the source
+    /// followed by `map`, `filter`, `filter_map` and `take`, `terminal_args`
+    /// are the terminal's arguments, like the predicate of `find`.
+    /// `position` gives the 0 based index rust would, indexing adds the 1.
+    fn chain(&mut self, iter: &ast::Expr, terminal: &str, terminal_args: &[P<Expr>]) {
+        let (source, mut adapters) = adapters(iter);
+        let repeat = repeated(source);
+        // repeat(x).take(n) is a counted loop
+        let count = match adapters.first() {
+            Some((name, count)) if repeat.is_some() && name == "take" => {
+                let count = *count;
+                adapters.remove(0);
+                Some(count)
+            }
+            _ => None,
+        };
+        self.push_str("(function()\n");
+        self.function(true, |g| {
+            g.curr_indent += 1;
+            let mut locals = 0;
+            let repeat = repeat.map(|value| g.once(&mut locals, value));
+            let count = count.map(|count| g.once(&mut locals, count));
+            let args: Vec<String> = adapters
+                .iter()
+                .map(|(_, arg)| g.once(&mut locals, arg))
+                .collect();
+            // the fold's accumulator holds its initial value already
+            let (init, terminal_args) = match terminal_args.split_first() {
+                Some((init, rest)) if terminal == "fold" => (Some(&**init), rest),
+                _ => (None, terminal_args),
+            };
+            let terminal_args: Vec<String> = terminal_args
+                .iter()
+                .map(|arg| g.once(&mut locals, arg))
+                .collect();
+            if terminal == "collect" || terminal == "concat" {
+                g.lines("local __rua_t = {}");
+            }
+            // indices stay 0 based like rust's, indexing adds the 1
+            if terminal == "position" {
+                g.lines("local __rua_i = 0");
+            }
+            if let Some(init) = init {
+                g.indent();
+                g.push_str("local __rua_acc = ");
+                g.expr(init);
+                g.push_str("\n");
+            } else if terminal == "reduce" {
+                g.lines("local __rua_acc");
+            } else if terminal == "sum" {
+                g.lines("local __rua_acc = 0");
+            } else if terminal == "product" {
+                g.lines("local __rua_acc = 1");
+            }
+            for (i, (name, _)) in adapters.iter().enumerate() {
+                if name == "take" {
+                    g.lines(&format!("local __rua_n{} = 0", i + 1));
+                }
+            }
+            match (repeat, count) {
+                (Some(value), Some(count)) => g.lines(&format!(
+                    "for _ = 1, {} do\n  local __rua_x = {}",
+                    count, value
+                )),
+                (Some(value), None) => {
+                    g.lines(&format!("while true do\n  local __rua_x = {}", value))
+                }
+                _ => {
+                    g.indent();
+                    g.for_head("__rua_x", source);
+                }
+            }
+            g.curr_indent += 1;
+            let mut ifs = 0;
+            for (i, ((name, _), arg)) in adapters.iter().zip(&args).enumerate() {
+                match &**name {
+                    "map" => g.lines(&format!("local __rua_x = {}(__rua_x)", arg)),
+                    "take" => g.lines(&format!(
+                        "if __rua_n{0} == {1} then\n  break\nend\n__rua_n{0} = __rua_n{0} + 1",
+                        i + 1,
+                        arg
+                    )),
+                    "filter" => {
+                        g.lines(&format!("if {}(__rua_x) then", arg));
+                        ifs += 1;
+                        g.curr_indent += 1;
+                    }
+                    _ => {
+                        g.lines(&format!(
+                            "local __rua_x = {}(__rua_x)\nif __rua_x ~= nil then",
+                            arg
+                        ));
+                        ifs += 1;
+                        g.curr_indent += 1;
+                    }
+                }
+            }
+            let pred = terminal_args.last().cloned().unwrap_or_default();
+            g.lines(&match terminal {
+                "fold" => format!("__rua_acc = {}(__rua_acc, __rua_x)", pred),
+                // seeded from the first element, nil when there's none
+                "reduce" => format!(
+                    "if __rua_acc == nil then\n  __rua_acc = __rua_x\nelse\n  __rua_acc = {}(__rua_acc, __rua_x)\nend",
+                    pred
+                ),
+                "sum" => "__rua_acc = __rua_acc + __rua_x".to_string(),
+                "product" => "__rua_acc = __rua_acc * __rua_x".to_string(),
+                "find" => format!("if {}(__rua_x) then\n  return __rua_x\nend", pred),
+                "position" => format!(
+                    "if {}(__rua_x) then\n  return __rua_i\nend\n__rua_i = __rua_i + 1",
+                    pred
+                ),
+                "any" => format!("if {}(__rua_x) then\n  return true\nend", pred),
+                "all" => format!("if not {}(__rua_x) then\n  return false\nend", pred),
+                _ => "__rua_t[#__rua_t + 1] = __rua_x".to_string(),
+            });
+            for _ in 0..ifs {
+                g.curr_indent -= 1;
+                g.lines("end");
+            }
+            g.curr_indent -= 1;
+            g.lines("end");
+            match terminal {
+                "any" => g.lines("return false"),
+                "all" => g.lines("return true"),
+                "collect" => g.lines("return __rua_t"),
+                "fold" | "reduce" | "sum" | "product" => g.lines("return __rua_acc"),
+                "concat" => g.lines("return table.concat(__rua_t)"),
+                _ => {}
+            }
+            g.curr_indent -= 1;
+        });
+        self.indent();
+        self.push_str("end)()");
+    }
+
+    /// The lua for `expr` where it's used over and over, values that would
+    /// be made again, like closures, go in a local first.
+    fn once(&mut self, locals: &mut usize, expr: &ast::Expr) -> String {
+        if is_pure(expr) || matches!(expr.node, ExprKind::Path(..)) {
+            return self.capture(|g| g.expr(expr));
+        }
+        *locals += 1;
+        let name = format!("__rua_f{}", locals);
+        self.indent();
+        self.push_str(&format!("local {} = ", name));
+        self.expr(expr);
+        self.push_str("\n");
+        name
+    }
+
+    /// The elements of the iterator `iter` as a table.
+    fn iter_table(&mut self, iter: &ast::Expr) {
+        let (source, adapters) = adapters(iter);
+        if adapters.is_empty() && repeated(source).is_none() && chars_of(source).is_none() {
+            self.expr(iterable(iter));
+        } else {
+            self.collect(iter, false);
+        }
+    }
+
+    /// `iter.collect()`, into a table or joined into a string.
+    fn collect(&mut self, iter: &ast::Expr, string: bool) {
+        // a.chain(b) already makes a new table
+        if let ExprKind::MethodCall(method, _, args) = &iter.node {
+            if is_named(&method.node, "chain") && args.len() == 2 && !string {
+                return self.expr(iter);
+            }
+        }
+        match chars_of(iter) {
+            // s.chars().collect::<String>() is s again
+            Some((s, false)) if string => self.expr(s),
+            Some((s, true)) => {
+                self.helper(prelude::REV_CHARS);
+                self.push_str(if string { "table.concat(" } else { "" });
+                self.push_str("__rua_rev_chars(");
+                self.expr(s);
+                self.push_str(if string { "))" } else { ")" });
+            }
+            _ => self.chain(iter, if string { "concat" } else { "collect" }, &[]),
+        }
+    }
+
+    /// Emits each line of `lua` at the current indentation.
+    fn lines(&mut self, lua: &str) {
+        for line in lua.lines() {
+            self.indent();
+            self.push_str(line);
+            self.push_str("\n");
+        }
+    }
+
+    /// Emits `expr - 1`, for turning exclusive bounds into lua's inclusive ones.
+    fn minus_one(&mut self, expr: &ast::Expr) {
+        match &expr.node {
+            ExprKind::Lit(lit) => {
+                if let LitKind::Int(n, _) = lit.node {
+                    if n.low64() > 0 && n.high64() == 0 {
+                        return self.push_str(&format!("{}", n.low64() - 1));
+                    }
+                }
+            }
+            // 0..n + 1 is 0, n
+            ExprKind::Binary(op, lhs, rhs) if op.node == ast::BinOpKind::Add && is_int(rhs, 1) => {
+                return self.expr(lhs);
+            }
+            _ => {}
+        }
+        self.operand(expr, |p| p < precedence(ast::BinOpKind::Sub));
+        self.push_str(" - 1");
+    }
+
+    fn while_(&mut self, cond: &ast::Expr, block: &P<Block>, label: Option<ast::Name>) {
+        self.push_str("while ");
+        self.expr(cond);
+        self.push_str(" do\n");
+        self.loop_body(block, false, label);
+        self.end();
+        self.loop_label(block, label)
+    }
+
+    /// `while let` is a loop that breaks when the pattern stops matching.
+    fn while_let(
+        &mut self,
+        pat: &ast::Pat,
+        value: &ast::Expr,
+        block: &P<Block>,
+        label: Option<ast::Name>,
+    ) {
+        self.push_str("while true do\n");
+        self.curr_indent += 1;
+        let name = match &pat.node {
+            // Some(x) can bind the value straight away
+            PatKind::TupleStruct(path, pats, None)
+                if path_name(path) == "Some" && pats.len() == 1 =>
+            {
+                match &pats[0].node {
+                    PatKind::Ident(_, ident, None) => Some(ident.node.name),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let value = match name {
+            Some(name) => {
+                self.indent();
+                self.push_str(&format!("local {} = ", name));
+                self.expr(value);
+                self.push_str("\n");
+                name.to_string()
+            }
+            None if is_place(value) => self.capture(|g| g.expr(value)),
+            None => {
+                self.indent();
+                self.push_str("local __rua_v = ");
+                self.expr(value);
+                self.push_str("\n");
+                "__rua_v".to_string()
+            }
+        };
+        let mut tests = Vec::new();
+        self.pat_tests(pat, &value, &mut tests);
+        let test = match tests.as_slice() {
+            [] => None,
+            [test] if test.ends_with(" ~= nil") => {
+                Some(format!("{} == nil", test.trim_end_matches(" ~= nil")))
+            }
+            _ => Some(format!("not ({})", tests.join(" and "))),
+        };
+        if let Some(test) = test {
+            self.lines(&format!("if {} then\n  break\nend", test));
+        }
+        if name.is_none() {
+            self.pat_binds(pat, &value);
+        }
+        self.curr_indent -= 1;
+        self.loop_body(block, false, label);
+        self.end();
+        self.loop_label(block, label)
+    }
+
+    /// `value` loops are used as values, so `break x` returns x instead.
+    fn loop_(&mut self, block: &P<Block>, value: bool, label: Option<ast::Name>) {
+        // `loop { ..; if cond { break } }` is lua's repeat until, which is
+        // how rust spells do while. continue can't jump past locals the
+        // condition sees, so it keeps the label.
+        let repeat = post_condition(block).filter(|_| !value && !continues(block, label));
+        if let Some(cond) = repeat {
+            let mut body = (**block).clone();
+            body.stmts.pop();
+            self.push_str("repeat\n");
+            self.loop_body(&P(body), false, label);
+            self.indent();
+            self.push_str("until ");
+            self.expr(cond);
+        } else {
+            self.push_str("while true do\n");
+            self.loop_body(block, value, label);
+            self.end();
+        }
+        // loops used as values return instead
+        if !value {
+            self.loop_label(block, label);
+        }
+    }
+
+    fn loop_body(&mut self, block: &P<Block>, value: bool, label: Option<ast::Name>) {
+        self.loops.push((label, value));
+        let continues = continues(block, label);
+        let label = continue_label(self.loops.len());
+        if continues && block.stmts.last().is_some_and(is_return) {
+            // nothing can follow a return, so the label needs it in a block
+            self.curr_indent += 1;
+            self.indent();
+            self.push_str("do\n");
+            self.block(block);
+            self.end();
+            self.push_str("\n");
+            self.curr_indent -= 1;
+        } else {
+            self.block(block);
+        }
+        if continues {
+            self.curr_indent += 1;
+            self.indent();
+            self.push_str(&format!("::{}::\n", label));
+            self.curr_indent -= 1;
+        }
+        self.loops.pop();
+    }
+
+    /// The index in `loops` of the loop a break or continue to `label` is
+    /// for, the innermost one without a label.
+    fn target(&self, label: Option<ast::SpannedIdent>) -> Option<usize> {
+        match label {
+            Some(label) => self
+                .loops
+                .iter()
+                .rposition(|(name, _)| *name == Some(label.node.name)),
+            None => self.loops.len().checked_sub(1),
+        }
+    }
+
+    /// Emits the label `break 'a` jumps to from the loops inside loop `'a`.
+    fn loop_label(&mut self, block: &Block, label: Option<ast::Name>) {
+        if let Some(label) = label.filter(|&label| breaks_to(block, label)) {
+            self.push_str("\n");
+            self.indent();
+            self.push_str(&format!("::{}::", lua_label(label)));
+        }
+    }
+
+    fn end(&mut self) {
+        self.indent();
+        self.push_str("end");
+    }
+
+    fn op(&mut self, op: &BinOp, lhs: &P<Expr>, rhs: &P<Expr>) {
+        if op.node == ast::BinOpKind::Add && self.is_concat(lhs, rhs) {
+            // s + "x"
+            self.operand(lhs, |p| p < CONCAT_PRECEDENCE);
+            self.push_str(" .. ");
+            return self.operand(rhs, |p| p <= CONCAT_PRECEDENCE);
+        }
+        self.operand(lhs, |p| p < precedence(op.node));
+        self.op_rhs(op, lhs, rhs);
+    }
+
+    /// Whether calling `method` on `receiver` appends to a String.
+    fn is_append(&self, method: ast::Name, receiver: &ast::Expr) -> bool {
+        method == "push_str" || method == "push" && self.is_string(receiver)
+    }
+
+    /// Guesses if `lhs + rhs` adds to a String, from either side.
+    fn is_concat(&self, lhs: &ast::Expr, rhs: &ast::Expr) -> bool {
+        self.is_string(lhs)
+            || is_string_expr(lhs)
+            || self.is_text(rhs)
+            || is_string_expr(strip_ref(rhs))
+    }
+
+    /// Emits the operator and right hand side of a binary expression.
+    fn op_rhs(&mut self, op: &BinOp, lhs: &ast::Expr, rhs: &P<Expr>) {
+        let prec = precedence(op.node);
+        self.push_str(" ");
+        // lua's / always gives a float, // floors like rust's integer
+        // division does for positive numbers
+        if op.node == ast::BinOpKind::Div && (self.is_integral(lhs) || self.is_integral(rhs)) {
+            self.push_str("//");
+        } else {
+            self.push_str(lua_op(op.node));
+        }
+        self.push_str(" ");
+        if op.node == ast::BinOpKind::Sub && is_neg(rhs) {
+            // a - -b reads badly, a--b would be a comment
+            self.push_str("(");
+            self.expr(rhs);
+            self.push_str(")");
+        } else {
+            // lua's binary operators are left associative
+            self.operand(rhs, |p| p <= prec);
+        }
+    }
+
+    /// `a += b` is `a = a + b`, lua has no compound assignment.
+    fn assign_op(&mut self, op: &BinOp, lhs: &ast::Expr, rhs: &P<Expr>) {
+        if let ExprKind::Unary(ast::UnOp::Deref, entry) = &lhs.node {
+            if let Some((map, key, value)) = or_insert(entry) {
+                return self.entry_op(op, map, key, value, rhs);
+            }
+        }
+        // the target is written twice, so whatever in it has side effects
+        // is evaluated into locals first
+        let target = match &lhs.node {
+            ExprKind::Index(table, index) if !is_place(table) || !is_pure(index) => {
+                self.push_str("do\n");
+                self.curr_indent += 1;
+                self.indent();
+                self.push_str("local __rua_t, __rua_k = ");
+                self.expr(table);
+                self.push_str(", ");
+                self.index(index);
+                self.push_str("\n");
+                self.indent();
+                Some("__rua_t[__rua_k]".to_string())
+            }
+            ExprKind::Field(table, field) if !is_place(table) => {
+                self.push_str("do\n");
+                self.curr_indent += 1;
+                self.indent();
+                self.push_str("local __rua_t = ");
+                self.expr(table);
+                self.push_str("\n");
+                self.indent();
+                Some(format!("__rua_t.{}", field.node.name))
+            }
+            _ => None,
+        };
+        let scoped = target.is_some();
+        let target = match target {
+            Some(target) => target,
+            None => self.capture(|g| g.expr(lhs)),
+        };
+
+        self.push_str(&target);
+        self.push_str(" = ");
+        self.push_str(&target);
+        if op.node == ast::BinOpKind::Add && self.is_concat(lhs, rhs) {
+            // s += "x"
+            self.push_str(" .. ");
+            self.operand(rhs, |p| p <= CONCAT_PRECEDENCE);
+        } else {
+            self.op_rhs(op, lhs, rhs);
+        }
+
+        if scoped {
+            self.push_str("\n");
+            self.curr_indent -= 1;
+            self.end();
+        }
+    }
+
+    /// Emits an operand, parenthesizing binary expressions whose precedence
+    /// would let them be split apart by the surrounding operator.
+    fn operand<F: Fn(u8) -> bool>(&mut self, expr: &ast::Expr, needs_parens: F) {
+        match &expr.node {
+            ExprKind::Binary(op, ..) if needs_parens(precedence(op.node)) => {
+                self.push_str("(");
+                self.expr(expr);
+                self.push_str(")");
+            }
+            _ => self.expr(expr),
+        }
+    }
+
+    /// `*map.entry(key).or_insert(value) += rhs`, as
+    /// `map[key] = (map[key] or value) + rhs`.
+    fn entry_op(
+        &mut self,
+        op: &BinOp,
+        map: &ast::Expr,
+        key: &ast::Expr,
+        value: &ast::Expr,
+        rhs: &P<Expr>,
+    ) {
+        let scoped = !is_place(map) || !is_pure(key);
+        let target = if scoped {
+            self.push_str("do\n");
+            self.curr_indent += 1;
+            self.indent();
+            self.push_str("local __rua_t, __rua_k = ");
+            self.expr(map);
+            self.push_str(", ");
+            self.expr(key);
+            self.push_str("\n");
+            self.indent();
+            "__rua_t[__rua_k]".to_string()
+        } else {
+            self.capture(|g| {
+                g.receiver(map);
+                g.push_str("[");
+                g.expr(key);
+                g.push_str("]");
+            })
+        };
+        self.push_str(&format!("{} = ({} or ", target, target));
+        self.expr(value);
+        self.push_str(")");
+        self.op_rhs(op, value, rhs);
+        if scoped {
+            self.push_str("\n");
+            self.curr_indent -= 1;
+            self.end();
+        }
+    }
+
+    /// Emits a 0 based rust index as a 1 based lua one.
+    fn index(&mut self, index: &ast::Expr) {
+        match &index.node {
+            ExprKind::Lit(lit) => match lit.node {
+                LitKind::Int(n, _) => return self.push_str(&format!("{}", n.low64() + 1)),
+                // map["key"]
+                LitKind::Str(..) => return self.literal(lit),
+                _ => {}
+            },
+            // v[i - 1] is just v[i]
+            ExprKind::Binary(op, lhs, rhs) if op.node == ast::BinOpKind::Sub && is_int(rhs, 1) => {
+                return self.expr(lhs);
+            }
+            // v[i + 1] is v[i + 2]
+            ExprKind::Binary(op, lhs, rhs)
+                if op.node == ast::BinOpKind::Add && int(rhs).is_some() =>
+            {
+                self.operand(lhs, |p| p < precedence(ast::BinOpKind::Add));
+                self.push_str(&format!(" + {}", int(rhs).unwrap() + 1));
+                return;
+            }
+            // + binds tighter than the bitwise and comparison operators
+            ExprKind::Binary(op, ..) if !is_arithmetic(op.node) => {
+                self.push_str("(");
+                self.expr(index);
+                self.push_str(") + 1");
+                return;
+            }
+            _ => {}
+        }
+        self.expr(index);
+        self.push_str(" + 1");
+    }
+
+    /// `v[a..b]`, slices are copies since lua has no views into tables or
+    /// strings.
+    fn slice(
+        &mut self,
+        expr: &ast::Expr,
+        start: &Option<P<Expr>>,
+        end: &Option<P<Expr>>,
+        limits: ast::RangeLimits,
+    ) {
+        self.helper(prelude::SLICE);
+        self.push_str("__rua_slice(");
+        self.expr(expr);
+        self.push_str(", ");
+        match start {
+            Some(start) => self.index(start),
+            None => self.push_str("1"),
+        }
+        if let Some(end) = end {
+            self.push_str(", ");
+            match limits {
+                ast::RangeLimits::HalfOpen => self.expr(end),
+                ast::RangeLimits::Closed => self.index(end),
+            }
+        }
+        self.push_str(")");
+    }
+
+    fn unary(&mut self, op: ast::UnOp, expr: &P<Expr>) {
+        match op {
+            ast::UnOp::Neg => {
+                self.push_str("-");
+                if is_neg(expr) {
+                    // --x would be a comment
+                    self.push_str("(");
+                    self.expr(expr);
+                    self.push_str(")");
+                } else {
+                    self.operand(expr, |_| true);
+                }
+            }
+            ast::UnOp::Not => {
+                self.push_str("not ");
+                self.operand(expr, |_| true);
+            }
+            // everything is a reference in lua
+            ast::UnOp::Deref => self.expr(expr),
+        }
+    }
+
+    fn expr(&mut self, expr: &ast::Expr) {
+        match &expr.node {
+            // hoisted before a `?` that runs after it
+            _ if !matches!(expr.node, ExprKind::Try(_)) && self.tries.contains_key(&expr.span) => {
+                let name = self.tries[&expr.span].clone();
+                self.push_str(&name)
+            }
+            ExprKind::Lit(literal) => self.literal(literal),
+            ExprKind::Path(_, path) => self.path(path),
+            // lua drops the error of Err(e) anywhere but the end of a list,
+            // so only returns and lets give it, see result_value
+            ExprKind::Call(..) if err_payload(expr).is_some() => {
+                self.unsupported(expr.span, "Err anywhere but a return or let")
+            }
+            ExprKind::Call(func, args) => self.call(func, args),
+            ExprKind::MethodCall(method, tys, args) => {
+                let name = method.node.name;
+                if args.len() == 1 && IDENTITY_METHODS.contains(&&*name.as_str()) {
+                    return self.expr(&args[0]);
+                }
+                if self.is_set(&args[0]) {
+                    if let Some((_, _, lua)) = SET_METHODS
+                        .iter()
+                        .find(|(rust, len, _)| name == *rust && args.len() == *len)
+                    {
+                        return self.template(lua, args);
+                    }
+                }
+                // entry is lowered along with its or_insert, clone copies
+                // any table
+                if self.is_map(&args[0]) && name != "entry" && name != "clone" {
+                    return match MAP_METHODS
+                        .iter()
+                        .find(|(rust, len, _)| name == *rust && args.len() == *len)
+                    {
+                        Some((_, _, lua)) => self.template(lua, args),
+                        None if map_update(name, args).is_some() => {
+                            self.unsupported(expr.span, "map update as a value")
+                        }
+                        None => self.unsupported(expr.span, "map method"),
+                    };
+                }
+                if BOUNDED_OPS.contains(&&*name.as_str()) && args.len() == 2 {
+                    return self.bounded_op(&name.as_str(), args);
+                }
+                // a table of the bytes, so indexing and len work like on a slice
+                if (name == "as_bytes" || name == "bytes") && args.len() == 1 {
+                    self.push_str("{");
+                    self.receiver(&args[0]);
+                    return self.push_str(":byte(1, -1)}");
+                }
+                // lua strings are values already
+                if name == "clone" && args.len() == 1 && self.is_string(&args[0]) {
+                    return self.expr(&args[0]);
+                }
+                // a slice's elements are copied into a new table, deeper
+                // copies are for clone on everything else
+                if name == "clone" && args.len() == 1 && self.is_slice(&args[0]) {
+                    return self.template("__rua_copy({0})", args);
+                }
+                if let Some((map, key, value)) = or_insert(expr) {
+                    self.helper(prelude::OR_INSERT);
+                    self.push_str("__rua_or_insert(");
+                    for (i, arg) in [map, key, value].iter().enumerate() {
+                        if i != 0 {
+                            self.push_str(", ");
+                        }
+                        self.expr(arg);
+                    }
+                    return self.push_str(")");
+                }
+                // `(a..=b).contains(&x)` is a bounds check, not a table scan
+                if name == "contains" && args.len() == 2 && is_pure(&args[1]) {
+                    if let Some((start, end, limits)) = bounded_range(&args[0]) {
+                        let upper = match limits {
+                            ast::RangeLimits::HalfOpen => "<",
+                            ast::RangeLimits::Closed => "<=",
+                        };
+                        let args = [P(start.clone()), args[1].clone(), P(end.clone())];
+                        let template = format!("({{1}} >= {{0}} and {{1}} {} {{2}})", upper);
+                        return self.template(&template, &args);
+                    }
+                }
+                // strings we know about are searched, anything else is taken
+                // to be a vector and scanned
+                if name == "contains"
+                    && args.len() == 2
+                    && !self.is_text(&args[0])
+                    && bounded_range(&args[0]).is_none()
+                {
+                    return self.template("__rua_contains({0}, {1})", args);
+                }
+                // out of bounds and missing keys are both nil, which is None.
+                // maps take their key by reference, vectors take a plain index
+                if name == "get" && args.len() == 2 {
+                    self.receiver(&args[0]);
+                    self.push_str("[");
+                    match &args[1].node {
+                        ExprKind::AddrOf(_, key) => self.expr(key),
+                        _ if self.is_string(&args[1]) => self.expr(&args[1]),
+                        _ => self.index(&args[1]),
+                    }
+                    return self.push_str("]");
+                }
+                // lua strings can't change, appending makes a new one
+                if self.is_append(name, &args[0]) && args.len() == 2 {
+                    if !is_place(&args[0]) {
+                        return self.unsupported(
+                            args[0].span,
+                            "push_str onto a string that isn't a local",
+                        );
+                    }
+                    return self.template("{0} = {0} .. {1}", args);
+                }
+                if name == "parse" && args.len() == 1 && tys.is_empty() {
+                    let integer = self.integer_parse == Some(expr.span);
+                    return self.parse_number(integer, &args[0]);
+                }
+                if name == "parse" && args.len() == 1 {
+                    return self.parse(tys.first().map(|ty| &**ty), &args[0]);
+                }
+                // utf8.offset finds where the n-th character starts
+                if name == "nth"
+                    && args.len() == 2
+                    && chars_of(&args[0]).is_some_and(|(_, rev)| !rev)
+                {
+                    let (s, _) = chars_of(&args[0]).unwrap();
+                    self.helper(prelude::NTH_CHAR);
+                    self.push_str("__rua_nth_char(");
+                    self.expr(s);
+                    self.push_str(", ");
+                    self.expr(&args[1]);
+                    return self.push_str(")");
+                }
+                // Option::map, None is nil
+                if (name == "map" || name == "and_then")
+                    && args.len() == 2
+                    && !is_iterator(&args[0])
+                {
+                    return self.template("__rua_map({0}, {1})", args);
+                }
+                if ["find", "position", "any", "all"].contains(&&*name.as_str())
+                    && args.len() == 2
+                    && is_chain(&args[0])
+                {
+                    return self.chain(&args[0], &name.as_str(), &args[1..]);
+                }
+                if (name == "fold" && args.len() == 3 || name == "reduce" && args.len() == 2)
+                    && is_chain(&args[0])
+                {
+                    return self.chain(&args[0], &name.as_str(), &args[1..]);
+                }
+                // the turbofish on `sum::<i32>()` only names the type
+                if (name == "sum" || name == "product") && args.len() == 1 && is_chain(&args[0]) {
+                    return self.chain(&args[0], &name.as_str(), &[]);
+                }
+                if name == "chain" && args.len() == 2 && is_chain(&args[0]) {
+                    self.helper(prelude::CHAIN);
+                    self.push_str("__rua_chain(");
+                    self.iter_table(&args[0]);
+                    self.push_str(", ");
+                    self.iter_table(&args[1]);
+                    return self.push_str(")");
+                }
+                if name == "collect" && args.len() == 1 && is_chain(&args[0]) {
+                    let string = tys.first().is_some_and(|ty| is_string_ty(ty));
+                    return self.collect(&args[0], string);
+                }
+                // `cond.then(|| v)` evaluates v only when cond holds, like `and`
+                if name == "then" && args.len() == 2 {
+                    if let ExprKind::Closure(_, decl, body, _) = &args[1].node {
+                        if decl.inputs.is_empty() && !matches!(body.node, ExprKind::Block(..)) {
+                            let args = [args[0].clone(), body.clone()];
+                            return self.template("({0} and {1} or nil)", &args);
+                        }
+                    }
+                }
+                // writing to a String can't fail
+                if (name == "unwrap" || name == "expect") && self.is_string_write(&args[0]) {
+                    return self.expr(&args[0]);
+                }
+                // table.sort wants a less than, `|a, b| a.cmp(b)` is one
+                if (name == "sort_by" || name == "sort_unstable_by") && args.len() == 2 {
+                    if let ExprKind::Closure(_, decl, body, _) = &args[1].node {
+                        if let Some((a, b)) = comparison(body) {
+                            self.push_str("table.sort(");
+                            self.expr(&args[0]);
+                            self.push_str(", function");
+                            self.args(&decl.inputs);
+                            self.push_str(" return ");
+                            let prec = precedence(ast::BinOpKind::Lt);
+                            self.operand(a, |p| p < prec);
+                            self.push_str(" < ");
+                            self.operand(b, |p| p <= prec);
+                            return self.push_str(" end)");
+                        }
+                    }
+                }
+                if let Some((_, _, template)) = METHODS
+                    .iter()
+                    .find(|(method, arity, _)| name == *method && *arity == args.len())
+                {
+                    return self.template(template, args);
+                }
+
+                // args[0] is the receiver
+                self.receiver(&args[0]);
+                self.push_str(":");
+                self.push_str(&self.lua_name(name).as_str());
+                if self.varargs.contains(&name) {
+                    self.varargs_args(&args[1..]);
+                } else if self.optional.contains(&name) {
+                    self.tuple(without_nones(&args[1..]));
+                } else {
+                    self.tuple(&args[1..]);
+                }
+            }
+            // tuples are tables, so `pair.0` is `pair[1]`
+            ExprKind::Array(exprs) => self.table(exprs),
+            ExprKind::Repeat(elem, count) => self.fill(elem, count),
+            ExprKind::Tup(exprs) if exprs.is_empty() => self.push_str("nil"),
+            ExprKind::Tup(exprs) => self.table(exprs),
+            ExprKind::TupField(expr, index) => {
+                self.receiver(expr);
+                self.push_str(&format!("[{}]", index.node + 1));
+            }
+            ExprKind::Field(expr, ident) => {
+                self.receiver(expr);
+                self.push_str(".");
+                self.ident(&ident.node);
+            }
+            ExprKind::Index(expr, index) => match &index.node {
+                ExprKind::Range(start, end, limits) => self.slice(expr, start, end, *limits),
+                _ => {
+                    self.receiver(expr);
+                    self.push_str("[");
+                    self.index(index);
+                    self.push_str("]");
+                }
+            },
+            ExprKind::Paren(expr) => {
+                self.push_str("(");
+                self.expr(expr);
+                self.push_str(")");
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                self.op(op, lhs, rhs);
+            }
+            ExprKind::Unary(op, expr) => self.unary(*op, expr),
+            // everything is a reference in lua
+            ExprKind::AddrOf(_, expr) => self.expr(expr),
+            ExprKind::Cast(expr, ty) => self.cast(expr, ty),
+            ExprKind::Struct(path, fields, base) => self.struct_(path, fields, base),
+            // ranges used as values are tables of their numbers
+            ExprKind::Range(Some(start), Some(end), limits) => {
+                self.helper(prelude::RANGE);
+                self.push_str("__rua_range(");
+                self.expr(start);
+                self.push_str(", ");
+                match limits {
+                    ast::RangeLimits::HalfOpen => self.minus_one(end),
+                    ast::RangeLimits::Closed => self.expr(end),
+                }
+                self.push_str(")");
+            }
+            ExprKind::Closure(_, decl, body, _) => self.closure(decl, body),
+            // writing to a String never fails, so there's nothing to propagate
+            ExprKind::Try(inner) if self.is_string_write(inner) => self.expr(inner),
+            ExprKind::Try(_) if self.tries.contains_key(&expr.span) => {
+                let name = self.tries[&expr.span].clone();
+                self.push_str(&name)
+            }
+            // ?s that only might run would need their checks in the branch
+            ExprKind::Try(_) if self.can_try() => self.unsupported(expr.span, "conditional ?"),
+            ExprKind::Try(_) => self.unsupported(expr.span, "? outside a fn returning Option"),
+            // (a, b) = (b, a) is lua's multiple assignment
+            ExprKind::Assign(a, b) => match (&a.node, &b.node) {
+                (ExprKind::Tup(targets), ExprKind::Tup(values))
+                | (ExprKind::Array(targets), ExprKind::Array(values)) => {
+                    self.exprs(targets);
+                    self.push_str(" = ");
+                    self.exprs(values);
+                }
+                // fns return tuples as multiple values
+                (ExprKind::Tup(targets), ExprKind::Call(..) | ExprKind::MethodCall(..)) => {
+                    self.exprs(targets);
+                    self.push_str(" = ");
+                    self.expr(b);
+                }
+                (ExprKind::Tup(targets), _) | (ExprKind::Array(targets), _) => {
+                    self.exprs(targets);
+                    self.push_str(" = table.unpack(");
+                    self.expr(b);
+                    self.push_str(")");
+                }
+                _ => {
+                    self.expr(a);
+                    self.push_str(" = ");
+                    self.expr(b);
+                }
+            },
+            ExprKind::AssignOp(op, a, b) => self.assign_op(op, a, b),
+
+            ExprKind::Ret(_) if self.in_iife => self.unsupported(
+                expr.span,
+                "return inside an if, loop or block used as a value",
+            ),
+            ExprKind::Ret(val) => {
+                if let Some(ret) = val.as_ref().filter(|ret| !is_unit(ret)) {
+                    self.return_value(ret);
+                } else if self.explicit_nil {
+                    self.push_str("return nil");
+                } else {
+                    self.push_str("return");
+                }
+            }
+
+            ExprKind::Break(Some(label), val)
+                if self.blocks.iter().any(|(name, _)| *name == label.node.name) =>
+            {
+                let value = self
+                    .blocks
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| *name == label.node.name);
+                match (value.unwrap().1, val) {
+                    (true, Some(val)) => {
+                        self.push_str("return ");
+                        self.expr(val);
+                    }
+                    (true, None) => self.push_str("return"),
+                    (false, None) => {
+                        let label = lua_label(label.node.name);
+                        self.push_str(&format!("goto {}", label));
+                    }
+                    (false, Some(_)) => {
+                        self.unsupported(expr.span, "break with a value out of a statement block")
+                    }
+                }
+            }
+            ExprKind::Break(label, val) => match (self.target(*label), val) {
+                (None, _) => {
+                    self.unsupported(expr.span, "break out of an if or block used as a value")
+                }
+                (Some(depth), Some(val)) if self.loops[depth].1 => {
+                    self.push_str("return ");
+                    self.expr(val);
+                }
+                (Some(depth), _) if depth + 1 == self.loops.len() => self.push_str("break"),
+                (Some(depth), _) if self.loops[depth].1 => self.push_str("return"),
+                // lua's break only leaves the innermost loop, jump past the
+                // end of the outer one instead
+                (Some(_), _) => {
+                    let label = lua_label(label.unwrap().node.name);
+                    self.push_str(&format!("goto {}", label));
+                }
+            },
+
+            // lua has no continue, jump to the end of the loop body instead
+            ExprKind::Continue(label) => match self.target(*label) {
+                Some(depth) => {
+                    let label = continue_label(depth + 1);
+                    self.push_str(&format!("goto {}", label));
+                }
+                None => {
+                    self.unsupported(expr.span, "continue out of an if or block used as a value")
+                }
+            },
+
+            ExprKind::Block(block) => match block.stmts.as_slice() {
+                [ast::Stmt {
+                    node: StmtKind::Expr(expr),
+                    ..
+                }] => {
+                    self.push_str("(");
+                    self.expr(expr);
+                    self.push_str(")");
+                }
+                _ => self.iife(expr),
+            },
+            ExprKind::If(..)
+            | ExprKind::IfLet(..)
+            | ExprKind::Match(..)
+            | ExprKind::Loop(..)
+            | ExprKind::ForLoop(..) => self.iife(expr),
+
+            ExprKind::Mac(mac) => self.mac(mac),
+
+            _ => self.unsupported(expr.span, "expr"),
+        }
+    }
+
+    /// `s.parse::<T>()`, anything but an integer `T` is parsed as a float.
+    fn cast(&mut self, expr: &ast::Expr, ty: &ast::Ty) {
+        // chars are one character strings, all they convert to and from
+        // is their code point
+        if is_char_ty(ty) {
+            self.push_str("utf8.char(");
+            self.expr(uncast(expr));
+            return self.push_str(")");
+        }
+        if self.is_char(expr) {
+            if let ExprKind::Lit(lit) = &expr.node {
+                if let LitKind::Char(c) = lit.node {
+                    return self.push_str(&(c as u32).to_string());
+                }
+            }
+            self.push_str("utf8.codepoint(");
+            self.expr(expr);
+            return self.push_str(")");
+        }
+        match &ty.node {
+            // lua numbers convert themselves
+            TyKind::Path(None, path)
+                if path.segments.len() == 1
+                    && is_number(&path.segments[0].identifier.name.as_str()) =>
+            {
+                self.expr(expr)
+            }
+            _ => self.unsupported(ty.span, "cast"),
+        }
+    }
+
+    fn parse(&mut self, ty: Option<&ast::Ty>, s: &ast::Expr) {
+        let integer = match ty.map(|ty| &ty.node) {
+            Some(TyKind::Path(None, path)) if path.segments.len() == 1 => {
+                is_integer(&path.segments[0].identifier.name.as_str())
+            }
+            _ => false,
+        };
+        self.parse_number(integer, s);
+    }
+
+    fn parse_number(&mut self, integer: bool, s: &ast::Expr) {
+        // both give nil for the Err case
+        if integer {
+            self.push_str("math.tointeger(tonumber(");
+            self.expr(s);
+            self.push_str("))");
+        } else {
+            self.push_str("tonumber(");
+            self.expr(s);
+            self.push_str(")");
+        }
+    }
+
+    /// Emits a method from `METHODS`, where `{0}` is the receiver and
+    /// `{1}`.. are the arguments, `{1:index}` is an argument used as an index.
+    fn template(&mut self, template: &str, args: &[P<Expr>]) {
+        self.helpers_in(template);
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            self.push_str(&rest[..start]);
+            let end = start + rest[start..].find('}').unwrap();
+            let (n, index) = match rest[start + 1..end].split_once(':') {
+                Some((n, kind)) => (n, kind == "index"),
+                None => (&rest[start + 1..end], false),
+            };
+            let arg = strip_ref(&args[n.parse::<usize>().unwrap()]);
+            rest = &rest[end + 1..];
+            if index {
+                // table.remove({0}, {1:index})
+                self.index(arg);
+            } else if self.buf.ends_with('#') || rest.starts_with(|c| ":.[(".contains(c)) {
+                // {0}:upper(), #{1}, {1}()
+                self.receiver(arg);
+            } else if (self.buf.ends_with('(') || self.buf.ends_with(", "))
+                && rest.starts_with(|c| "),".contains(c))
+            {
+                // math.abs({0}), parens around an argument are redundant
+                match &arg.node {
+                    ExprKind::Paren(arg) => self.expr(arg),
+                    _ => self.expr(arg),
+                }
+            } else {
+                // {0} ^ {1}
+                self.operand(arg, |_| true);
+            }
+        }
+        self.push_str(rest);
+    }
+
+    /// Emits an expression in statement position, where ifs, loops and
+    /// blocks can be emitted as lua statements.
+    fn expr_stmt(&mut self, expr: &ast::Expr) {
+        match &expr.node {
+            ExprKind::If(..) | ExprKind::IfLet(..) => self.if_(expr, false),
+            ExprKind::Match(value, arms) => self.match_(value, arms, false),
+            // `break 'a` jumps past the end of the block
+            ExprKind::Loop(block, Some(label)) if self.labeled_blocks.contains(&label.span.lo) => {
+                self.blocks.push((label.node.name, false));
+                self.push_str("do\n");
+                self.block(block);
+                self.end();
+                self.blocks.pop();
+                self.push_str("\n");
+                self.indent();
+                self.push_str(&format!("::{}::", lua_label(label.node.name)));
+            }
+            ExprKind::Loop(block, label) => {
+                self.loop_(block, false, label.map(|label| label.node.name))
+            }
+            ExprKind::ForLoop(pat, iter, block, label) => {
+                self.for_(pat, iter, block, label.map(|label| label.node.name))
+            }
+            ExprKind::While(cond, block, label) => {
+                self.while_(cond, block, label.map(|label| label.node.name))
+            }
+            ExprKind::WhileLet(pat, value, block, label) => {
+                self.while_let(pat, value, block, label.map(|label| label.node.name))
+            }
+            ExprKind::Block(block) => {
+                self.push_str("do\n");
+                self.block(block);
+                self.end()
+            }
+            ExprKind::Assign(..)
+            | ExprKind::AssignOp(..)
+            | ExprKind::Ret(_)
+            | ExprKind::Break(..)
+            | ExprKind::Continue(_)
+            | ExprKind::Mac(_)
+            | ExprKind::Try(_) => self.expr(expr),
+            // `write!(s, ..).unwrap()` is just the append
+            ExprKind::MethodCall(_, _, args) if self.is_string_write(&args[0]) => self.expr(expr),
+            // so are appends to strings
+            ExprKind::MethodCall(method, _, args) if self.is_append(method.node.name, &args[0]) => {
+                self.expr(expr)
+            }
+            // set updates are assignments
+            ExprKind::MethodCall(method, _, args)
+                if self.is_set(&args[0])
+                    && ["insert", "remove", "clear"].contains(&&*method.node.name.as_str()) =>
+            {
+                self.expr(expr)
+            }
+            // so are map updates
+            ExprKind::MethodCall(method, _, args)
+                if self.is_map(&args[0]) && map_update(method.node.name, args).is_some() =>
+            {
+                self.template(map_update(method.node.name, args).unwrap(), args)
+            }
+            _ => {
+                // only calls are statements in lua, other values need
+                // somewhere to go
+                let lua = self.capture(|g| g.expr(expr));
+                let call = matches!(expr.node, ExprKind::Call(..) | ExprKind::MethodCall(..));
+                if !call || !lua.ends_with(')') {
+                    self.push_str("local _ = ");
+                }
+                self.push_str(&lua);
+            }
+        }
+    }
+
+    fn closure(&mut self, decl: &ast::FnDecl, body: &ast::Expr) {
+        self.push_str("function");
+        self.args(&decl.inputs);
+        let body = self.capture(|g| {
+            g.function(false, |g| {
+                g.returns = returns(decl);
+                match &body.node {
+                    ExprKind::Block(block) => g.return_block(block),
+                    _ => {
+                        g.curr_indent += 1;
+                        g.indent();
+                        g.ret(body);
+                        g.push_str("\n");
+                        g.curr_indent -= 1;
+                    }
+                }
+            })
+        });
+        // keep closures with a simple body on one line
+        let line = body.trim();
+        if !line.contains('\n') {
+            self.push_str(" ");
+            self.push_str(line);
+            self.push_str(if line.is_empty() { "end" } else { " end" });
+        } else {
+            self.push_str("\n");
+            self.push_str(&body);
+            self.end();
+        }
+    }
+
+    fn call(&mut self, func: &ast::Expr, args: &[P<Expr>]) {
+        if let ExprKind::Path(None, path) = &func.node {
+            match path_name(path).as_str() {
+                // lua has no capacities to reserve
+                "String::new" | "String::with_capacity" => return self.push_str("''"),
+                "Vec::new"
+                | "VecDeque::new"
+                | "Vec::with_capacity"
+                | "VecDeque::with_capacity"
+                | "HashMap::new"
+                | "HashMap::with_capacity"
+                | "BTreeMap::new"
+                | "HashSet::new"
+                | "HashSet::with_capacity"
+                | "BTreeSet::new" => return self.push_str("{}"),
+                "String::from" if args.len() == 1 => return self.expr(&args[0]),
+                // lua values live on the heap already, eg. boxed closures
+                "Box::new" if args.len() == 1 => return self.expr(&args[0]),
+                "char::from_u32" | "char::from" | "std::char::from_u32" if args.len() == 1 => {
+                    self.push_str("utf8.char(");
+                    self.expr(uncast(&args[0]));
+                    return self.push_str(")");
+                }
+                _ if path.segments.len() == 2
+                    && is_named(&path.segments[1].identifier, "default")
+                    && !is_named(&path.segments[0].identifier, "Default")
+                    && args.is_empty() =>
+                {
+                    let ty = self.type_name(&path.segments[0].identifier);
+                    return self.push_str(&default_of(&ty.as_str()));
+                }
+                // options are the value or nil
+                "Some" if args.len() == 1 => return self.expr(&args[0]),
+                // results are the value, or nil and the error
+                "Ok" if args.len() == 1 => return self.expr(&args[0]),
+                "std::iter::once" | "iter::once" | "once" if args.len() == 1 => {
+                    return self.table(args)
+                }
+                "std::mem::swap" | "mem::swap" if args.len() == 2 => {
+                    let (a, b) = (strip_ref(&args[0]), strip_ref(&args[1]));
+                    self.expr(a);
+                    self.push_str(", ");
+                    self.expr(b);
+                    self.push_str(" = ");
+                    self.expr(b);
+                    self.push_str(", ");
+                    self.expr(a);
+                    return;
+                }
+                "std::mem::replace" | "mem::replace" if args.len() == 2 => {
+                    return self.replace(strip_ref(&args[0]), |g| g.expr(&args[1]));
+                }
+                // what's taken is usually a String or a Vec
+                "std::mem::take" | "mem::take" if args.len() == 1 => {
+                    let place = strip_ref(&args[0]);
+                    let default = if self.is_string(place) { "''" } else { "{}" };
+                    return self.replace(place, |g| g.push_str(default));
+                }
+                _ if path.segments.len() == 2
+                    && is_named(&path.segments[1].identifier, "from_str")
+                    && args.len() == 1 =>
+                {
+                    let ty = path.segments[0].identifier.name;
+                    return self.parse_number(is_integer(&ty.as_str()), &args[0]);
+                }
+                _ => {}
+            }
+        }
+
+        self.expr(func);
+        let name = match &func.node {
+            ExprKind::Path(None, path) => Some(path.segments.last().unwrap().identifier.name),
+            _ => None,
+        };
+        match name {
+            Some(name) if self.varargs.contains(&name) => self.varargs_args(args),
+            // missing arguments are nil in lua
+            Some(name) if self.optional.contains(&name) => self.tuple(without_nones(args)),
+            _ => self.tuple(args),
+        }
+    }
+
+    /// Sets `place` to what `value` emits in a function returning what it
+    /// was before.
+    fn replace<F: FnOnce(&mut Self)>(&mut self, place: &ast::Expr, value: F) {
+        let place = self.capture(|g| g.expr(place));
+        self.push_str("(function()\n");
+        self.function(true, |g| {
+            g.curr_indent += 1;
+            g.lines(&format!("local __rua_old = {}", place));
+            g.indent();
+            g.push_str(&format!("{} = ", place));
+            value(g);
+            g.push_str("\n");
+            g.lines("return __rua_old");
+            g.curr_indent -= 1;
+        });
+        self.indent();
+        self.push_str("end)()");
+    }
+
+    /// Emits the arguments of a call to a `#[lua(varargs)]` fn, spreading
+    /// the slice passed last.
+    fn varargs_args(&mut self, args: &[P<Expr>]) {
+        let (rest, args) = match args.split_last() {
+            Some(split) => split,
+            None => return self.push_str("()"),
+        };
+        self.push_str("(");
+        self.exprs(args);
+        if !args.is_empty() {
+            self.push_str(", ");
+        }
+        match &strip_ref(rest).node {
+            ExprKind::Array(exprs) => self.exprs(exprs),
+            _ => {
+                self.push_str("table.unpack(");
+                self.expr(rest);
+                self.push_str(")");
+            }
+        }
+        self.push_str(")");
+    }
+
+    /// Emits the receiver of a method call or index, lua only allows these
+    /// on names, calls, fields and parenthesized expressions. Rust calls
+    /// can come out as anything, `v.len()` is `#v`, so it looks at the lua.
+    fn receiver(&mut self, expr: &ast::Expr) {
+        let lua = self.capture(|g| g.expr(expr));
+        if is_prefix_expr(&lua) {
+            self.push_str(&lua);
+        } else {
+            self.push_str(&format!("({})", lua));
+        }
+    }
+
+    /// Parses the comma separated arguments of a macro invocation.
+    fn mac_args(&self, mac: &ast::Mac) -> Vec<P<Expr>> {
+        let mut parser = syntax::parse::stream_to_parser(self.sess, mac.node.stream());
+        let mut args = Vec::new();
+        while parser.token != token::Eof {
+            match parser.parse_expr() {
+                Ok(arg) => args.push(arg),
+                Err(mut e) => {
+                    e.emit();
+                    panic!("errors while parsing macro arguments");
+                }
+            }
+            if !parser.eat(&token::Comma) {
+                break;
+            }
+        }
+        args
+    }
+
+    /// `matches!(value, pat)` is the test for `pat`, in a function taking
+    /// the value if it isn't already a place we can look at.
+    fn matches(&mut self, mac: &ast::Mac) {
+        let mut parser = syntax::parse::stream_to_parser(self.sess, mac.node.stream());
+        let value = parsed(parser.parse_expr());
+        parsed(parser.expect(&token::Comma));
+        let mut pats = vec![parsed(parser.parse_pat())];
+        while parser.eat(&token::BinOp(token::Or)) {
+            pats.push(parsed(parser.parse_pat()));
+        }
+        let guard = if parser.eat_keyword(keywords::If) {
+            Some(parsed(parser.parse_expr()))
+        } else {
+            None
+        };
+        if is_place(&value) {
+            let err = self.result_local(&value).map(result_err);
+            let value = self.capture(|g| g.expr(&value));
+            let (test, _) = self.arm_test(&pats, guard.as_deref(), &value, err.as_deref());
+            self.push_str(&format!("({})", test));
+        } else {
+            let (test, _) = self.arm_test(&pats, guard.as_deref(), "__rua_v", None);
+            self.push_str(&format!("(function(__rua_v) return {} end)(", test));
+            self.expr(&value);
+            self.push_str(")");
+        }
+    }
+
+    /// `vec![a, b]` is a table, `vec![x; n]` a table filled in a loop.
+    fn vec(&mut self, mac: &ast::Mac) {
+        let mut parser = syntax::parse::stream_to_parser(self.sess, mac.node.stream());
+        let mut elems = Vec::new();
+        while parser.token != token::Eof {
+            elems.push(parsed(parser.parse_expr()));
+            if elems.len() == 1 && parser.eat(&token::Semi) {
+                let count = parsed(parser.parse_expr());
+                return self.fill(&elems[0], &count);
+            }
+            if !parser.eat(&token::Comma) {
+                break;
+            }
+        }
+        self.table(&elems);
+    }
+
+    /// A table of `count` copies of `elem`, for `vec![elem; count]` and
+    /// `[elem; count]`. Every copy has to be its own value, filling it with
+    /// the same table would have each row alias the others, so new tables
+    /// (struct literals too) are made once per element and other tables
+    /// are cloned.
+    fn fill(&mut self, elem: &ast::Expr, count: &ast::Expr) {
+        let scalar = matches!(
+            elem.node,
+            ExprKind::Lit(_) | ExprKind::Binary(..) | ExprKind::Unary(ast::UnOp::Neg, _)
+        );
+        let fresh = match &elem.node {
+            ExprKind::Mac(mac) => is_named(&mac.node.path.segments[0].identifier, "vec"),
+            ExprKind::Array(_) | ExprKind::Tup(_) | ExprKind::Struct(..) => true,
+            ExprKind::Call(func, _) => match &func.node {
+                ExprKind::Path(None, path) => matches!(
+                    &*path_name(path),
+                    "Vec::new" | "Vec::with_capacity" | "VecDeque::new" | "String::new"
+                ),
+                _ => false,
+            },
+            _ => false,
+        };
+        self.push_str("(function()\n");
+        self.function(true, |g| {
+            g.curr_indent += 1;
+            if !scalar && !fresh {
+                g.helper(prelude::CLONE);
+                g.indent();
+                g.push_str("local __rua_v = ");
+                g.expr(elem);
+                g.push_str("\n");
+            }
+            g.lines("local __rua_t = {}");
+            g.indent();
+            g.push_str("for _ = 1, ");
+            g.expr(count);
+            g.push_str(" do\n");
+            g.curr_indent += 1;
+            g.indent();
+            g.push_str("__rua_t[#__rua_t + 1] = ");
+            if scalar || fresh {
+                g.expr(elem);
+            } else {
+                g.push_str("__rua_clone(__rua_v)");
+            }
+            g.push_str("\n");
+            g.curr_indent -= 1;
+            g.lines("end\nreturn __rua_t");
+            g.curr_indent -= 1;
+        });
+        self.indent();
+        self.push_str("end)()");
+    }
+
+    fn mac(&mut self, mac: &ast::Mac) {
+        let name = mac.node.path.segments.last().unwrap().identifier.name;
+        // the pattern isn't an expression
+        if name == "matches" {
+            return self.matches(mac);
+        }
+        if name == "vec" {
+            return self.vec(mac);
+        }
+        let args = self.mac_args(mac);
+        match &*name.as_str() {
+            "format" => self.format_args(&args, ""),
+            "print" => {
+                self.push_str("io.write(");
+                self.format_args(&args, "");
+                self.push_str(")");
+            }
+            "println" if args.is_empty() => self.push_str("print()"),
+            "println" => {
+                // print already ends the line for us
+                self.push_str("print(");
+                self.format_args(&args, "");
+                self.push_str(")");
+            }
+            "eprint" => {
+                self.push_str("io.stderr:write(");
+                self.format_args(&args, "");
+                self.push_str(")");
+            }
+            "eprintln" => {
+                self.push_str("io.stderr:write(");
+                self.format_args(&args, "\n");
+                self.push_str(")");
+            }
+            "write" => self.write(&args, ""),
+            "writeln" => self.write(&args, "\n"),
+            "panic" if args.is_empty() => self.push_str("error('explicit panic')"),
+            "unreachable" if args.is_empty() => self.push_str("error('unreachable')"),
+            // assert!(false) always fails, so control can't make it past
+            "assert" if is_assert_false(mac) && args.len() == 1 => {
+                self.push_str("error('assertion failed: false')")
+            }
+            "panic" | "unreachable" => {
+                self.push_str("error(");
+                self.format_args(&args, "");
+                self.push_str(")");
+            }
+            "assert" if is_assert_false(mac) => {
+                self.push_str("error(");
+                self.format_args(&args[1..], "");
+                self.push_str(")");
+            }
+            "assert" => {
+                self.push_str("assert(");
+                self.expr(&args[0]);
+                if args.len() > 1 {
+                    self.push_str(", ");
+                    self.format_args(&args[1..], "");
+                }
+                self.push_str(")");
+            }
+            "assert_eq" | "assert_ne" if args.len() >= 2 => {
+                let op = if name == "assert_eq" {
+                    ast::BinOpKind::Eq
+                } else {
+                    ast::BinOpKind::Ne
+                };
+                let prec = precedence(op);
+                self.push_str("assert(");
+                self.operand(&args[0], |p| p < prec);
+                self.push_str(&format!(" {} ", lua_op(op)));
+                self.operand(&args[1], |p| p <= prec);
+                if args.len() > 2 {
+                    self.push_str(", ");
+                    self.format_args(&args[2..], "");
+                }
+                self.push_str(")");
+            }
+            _ => self.unsupported(mac.span, "macro"),
+        }
+    }
+
+    /// write! appends to Strings we know about, anything else is assumed
+    /// to be a lua file handle.
+    fn write(&mut self, args: &[P<Expr>], suffix: &str) {
+        let (target, args) = args.split_first().expect("write! needs a target");
+        let target = strip_ref(target);
+
+        if self.is_string(target) {
+            self.expr(target);
+            self.push_str(" = ");
+            self.expr(target);
+            self.push_str(" .. ");
+            self.format_args(args, suffix);
+        } else {
+            self.expr(target);
+            self.push_str(":write(");
+            self.format_args(args, suffix);
+            self.push_str(")");
+        }
+    }
+
+    /// Whether `expr` is `write!` or `writeln!` into a String, which can't fail.
+    fn is_string_write(&self, expr: &ast::Expr) -> bool {
+        let mac = match &expr.node {
+            ExprKind::Mac(mac) => mac,
+            _ => return false,
+        };
+        let name = mac.node.path.segments.last().unwrap().identifier;
+        if !is_named(&name, "write") && !is_named(&name, "writeln") {
+            return false;
+        }
+        match self.mac_args(mac).first() {
+            Some(target) => self.is_string(strip_ref(target)),
+            None => false,
+        }
+    }
+
+    fn track_char(&mut self, name: ast::Name, char: bool) {
+        if char {
+            self.chars.insert(name);
+        } else {
+            self.chars.remove(&name);
+        }
+    }
+
+    fn is_set(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.sets.contains(&path.segments[0].identifier.name)
+            }
+            ExprKind::Field(_, field) => self.set_fields.contains(&field.node.name),
+            ExprKind::Paren(expr) | ExprKind::AddrOf(_, expr) => self.is_set(expr),
+            ExprKind::Unary(ast::UnOp::Deref, expr) => self.is_set(expr),
+            _ => false,
+        }
+    }
+
+    fn is_slice(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.slices.contains(&path.segments[0].identifier.name)
+            }
+            ExprKind::Paren(expr) | ExprKind::AddrOf(_, expr) => self.is_slice(expr),
+            ExprKind::Unary(ast::UnOp::Deref, expr) => self.is_slice(expr),
+            _ => false,
+        }
+    }
+
+    fn is_map(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.maps.contains(&path.segments[0].identifier.name)
+            }
+            ExprKind::Field(_, field) => self.map_fields.contains(&field.node.name),
+            ExprKind::Paren(expr) | ExprKind::AddrOf(_, expr) => self.is_map(expr),
+            ExprKind::Unary(ast::UnOp::Deref, expr) => self.is_map(expr),
+            _ => false,
+        }
+    }
+
+    fn track_int(&mut self, name: ast::Name, ty: Option<ast::Name>) {
+        match ty {
+            Some(ty) => self.ints.insert(name, ty),
+            None => self.ints.remove(&name),
+        };
+    }
+
+    /// Whether `expr` is an integer, of whatever type. Rust doesn't mix them
+    /// with floats, so one integer makes the whole operation one.
+    fn is_integral(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Lit(lit) => matches!(lit.node, LitKind::Int(..) | LitKind::Byte(_)),
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.integrals.contains(&path.segments[0].identifier.name)
+                    || self.int_of(expr).is_some()
+            }
+            ExprKind::MethodCall(method, _, args) if args.len() == 1 => {
+                is_named(&method.node, "len") || is_named(&method.node, "count")
+            }
+            ExprKind::Binary(op, a, b) if is_arithmetic(op.node) => {
+                self.is_integral(a) || self.is_integral(b)
+            }
+            ExprKind::Unary(ast::UnOp::Neg, expr) | ExprKind::Paren(expr) => self.is_integral(expr),
+            _ => self.int_of(expr).is_some(),
+        }
+    }
+
+    /// The integer type of `expr`, if it's obvious.
+    fn int_of(&self, expr: &ast::Expr) -> Option<ast::Name> {
+        match &expr.node {
+            ExprKind::Lit(lit) => match lit.node {
+                LitKind::Int(_, ast::LitIntType::Signed(ty)) => {
+                    Some(ast::Name::intern(ty.ty_to_string()))
+                }
+                LitKind::Int(_, ast::LitIntType::Unsigned(ty)) => {
+                    Some(ast::Name::intern(ty.ty_to_string()))
+                }
+                LitKind::Byte(_) => Some(ast::Name::intern("u8")),
+                _ => None,
+            },
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.ints.get(&path.segments[0].identifier.name).copied()
+            }
+            ExprKind::Cast(_, ty) => int_ty(ty),
+            ExprKind::Paren(expr) | ExprKind::Unary(ast::UnOp::Deref, expr) => self.int_of(expr),
+            _ => None,
+        }
+    }
+
+    /// `a.wrapping_add(b)` and `a.saturating_sub(b)`, by the bounds of the
+    /// type when we know it. Lua's integers wrap at 64 bits on their own,
+    /// other types are taken to be usize.
+    fn bounded_op(&mut self, method: &str, args: &[P<Expr>]) {
+        let (kind, op) = method.split_once('_').unwrap();
+        let op = match op {
+            "add" => "+",
+            "sub" => "-",
+            _ => "*",
+        };
+        let ty = self.int_of(&args[0]).or_else(|| self.int_of(&args[1]));
+        let (signed, bits) = ty.and_then(int_bits).unwrap_or((false, 64));
+        let sum = format!("{{0}} {} {{1}}", op);
+        let template = match (kind, signed) {
+            ("wrapping", _) if bits == 64 => sum,
+            ("wrapping", false) => format!("({}) % {}", sum, 1u64 << bits),
+            ("wrapping", true) => format!(
+                "({} + {half}) % {} - {half}",
+                sum,
+                1u64 << bits,
+                half = 1u64 << (bits - 1)
+            ),
+            (_, false) if op == "-" => format!("math.max({}, 0)", sum),
+            (_, false) if bits == 64 => sum,
+            (_, false) => format!("math.min({}, {})", sum, (1u64 << bits) - 1),
+            (_, true) if bits == 64 => {
+                format!(
+                    "math.max(math.min({}, math.maxinteger), math.mininteger)",
+                    sum
+                )
+            }
+            (_, true) => format!(
+                "math.max(math.min({}, {}), -{})",
+                sum,
+                (1u64 << (bits - 1)) - 1,
+                1u64 << (bits - 1)
+            ),
+        };
+        self.template(&template, args)
+    }
+
+    /// Whether `expr` is obviously a char.
+    fn is_char(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Lit(lit) => matches!(lit.node, LitKind::Char(_)),
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.chars.contains(&path.segments[0].identifier.name)
+            }
+            ExprKind::Cast(_, ty) => is_char_ty(ty),
+            ExprKind::Paren(expr) | ExprKind::Unary(ast::UnOp::Deref, expr) => self.is_char(expr),
+            _ => false,
+        }
+    }
+
+    fn is_string(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                self.strings.contains(&path.segments[0].identifier.name)
+            }
+            _ => false,
+        }
+    }
+
+    /// Emits the arguments of a formatting macro (`format!("{} {}", a, b)`)
+    /// as a string.format call, with `suffix` appended to the format string.
+    fn format_args(&mut self, args: &[P<Expr>], suffix: &str) {
+        let fmt = match args.first() {
+            None => String::new(),
+            Some(arg) => match &arg.node {
+                ExprKind::Lit(lit) => match lit.node {
+                    LitKind::Str(s, _) => s.as_str().to_string(),
+                    _ => return self.unsupported(arg.span, "format string"),
+                },
+                _ => return self.unsupported(arg.span, "format string"),
+            },
+        };
+
+        let mut positional = Vec::new();
+        let mut named = Vec::new();
+        for arg in args.iter().skip(1) {
+            match &arg.node {
+                // format!("{x}", x = 5)
+                ExprKind::Assign(name, value) => match &name.node {
+                    ExprKind::Path(None, path) if path.segments.len() == 1 => {
+                        named.push((path.segments[0].identifier.name.to_string(), value));
+                    }
+                    _ => return self.unsupported(arg.span, "format argument"),
+                },
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut pieces = format_pieces(&fmt);
+        pieces.push(Piece::Text(suffix.to_string()));
+
+        // `{x}` without an `x = ..` argument uses the variable x
+        let mut captures = Vec::new();
+        for piece in &pieces {
+            if let Piece::Arg {
+                arg: FormatArg::Named(name),
+                ..
+            } = piece
+            {
+                if !named.iter().any(|(n, _)| n == name) && !captures.iter().any(|(n, _)| n == name)
+                {
+                    let span = args[0].span;
+                    let path = ast::Path::from_ident(span, ast::Ident::from_str(name));
+                    let expr = P(Expr {
+                        id: ast::DUMMY_NODE_ID,
+                        node: ExprKind::Path(None, path),
+                        span,
+                        attrs: Vec::new().into(),
+                    });
+                    captures.push((name.clone(), expr));
+                }
+            }
+        }
+        named.extend(captures.iter().map(|(name, expr)| (name.clone(), expr)));
+
+        let mut pattern = String::new();
+        let mut values = Vec::new();
+        let mut next = 0;
+        for piece in &pieces {
+            match piece {
+                Piece::Text(text) => pattern.push_str(&text.replace('%', "%%")),
+                Piece::Arg {
+                    arg,
+                    directive,
+                    debug,
+                    aligned,
+                    pad,
+                } => {
+                    let value = match arg {
+                        FormatArg::Next => {
+                            next += 1;
+                            positional.get(next - 1).copied()
+                        }
+                        FormatArg::Index(i) => positional.get(*i).copied(),
+                        FormatArg::Named(name) => {
+                            named.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+                        }
+                    };
+                    let value = match value {
+                        Some(value) => value,
+                        None => {
+                            return self.unsupported(
+                                args[0].span,
+                                "format string without enough arguments",
+                            )
+                        }
+                    };
+                    let mut directive = match directive {
+                        Some(directive) => directive.clone(),
+                        None => {
+                            self.report(args[0].span, "format trait");
+                            "%s".to_string()
+                        }
+                    };
+                    // %d throws on floats, %.14g is how tostring writes them
+                    if directive.ends_with('d') && !self.is_integral(value) {
+                        directive.pop();
+                        directive.push_str(".14g");
+                    }
+                    // strings go on the left of their width, numbers the right
+                    let width = directive[1..].starts_with(|c: char| c.is_ascii_digit());
+                    if !aligned && width && directive.ends_with('s') && self.is_text(value) {
+                        directive.insert(1, '-');
+                    }
+                    // %s only takes strings and numbers before lua 5.2
+                    let (mut before, mut after) = if !directive.ends_with('s') {
+                        (String::new(), String::new())
+                    } else if *debug {
+                        self.helper(prelude::DEBUG_FMT);
+                        ("__rua_debug_fmt(".to_string(), ")".to_string())
+                    } else {
+                        ("tostring(".to_string(), ")".to_string())
+                    };
+                    if let Some(pad) = pad {
+                        if directive != "%s" {
+                            before = format!("string.format({}, ", lua_text(&directive));
+                            after = ")".to_string();
+                        }
+                        self.helper(prelude::PAD);
+                        before.insert_str(0, "__rua_pad(");
+                        after.push_str(&format!(
+                            ", {}, '{}', {})",
+                            lua_string(&pad.fill.to_string()),
+                            pad.align,
+                            pad.width
+                        ));
+                        directive = "%s".to_string();
+                    }
+                    pattern.push_str(&directive);
+                    values.push((value, before, after));
+                }
+            }
+        }
+
+        if values.is_empty() {
+            // nothing to format, a plain string will do
+            let text: String = pieces
+                .iter()
+                .map(|piece| match piece {
+                    Piece::Text(text) => text.as_str(),
+                    Piece::Arg { .. } => unreachable!(),
+                })
+                .collect();
+            self.push_str(&lua_text(&text));
+            return;
+        }
+
+        self.push_str("string.format(");
+        self.push_str(&lua_text(&pattern));
+        for (value, before, after) in values {
+            self.push_str(", ");
+            self.push_str(&before);
+            self.expr(value);
+            self.push_str(&after);
+        }
+        self.push_str(")");
+    }
+
+    /// Whether `expr` is known to be a string or char, rather than a number.
+    fn is_text(&self, expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Lit(lit) => matches!(lit.node, LitKind::Str(..) | LitKind::Char(_)),
+            ExprKind::AddrOf(_, expr) | ExprKind::Paren(expr) => self.is_text(expr),
+            _ => self.is_string(expr) || self.is_char(expr),
+        }
+    }
+
+    fn path(&mut self, path: &ast::Path) {
+        let name = path_name(path);
+        if let Some((_, lua)) = FUNCTIONS.iter().find(|(rust, _)| *rust == name) {
+            return self.push_str(lua);
+        }
+        if let Some(lua) = constant(path) {
+            return self.push_str(lua);
+        }
+        match path.segments.as_slice() {
+            [ident] if is_named(&ident.identifier, "None") => self.push_str("nil"),
+            [ident] if self.aliases.contains_key(&ident.identifier.name) => {
+                let alias = self.aliases[&ident.identifier.name].clone();
+                self.push_str(&alias);
+            }
+            [ident] => {
+                let name = self.lua_name(ident.identifier.name);
+                self.push_str(&name.as_str());
+            }
+            // lua sees the items of enclosing modules already
+            [first, ..]
+                if ["self", "super", "crate"]
+                    .iter()
+                    .any(|name| is_named(&first.identifier, name)) =>
+            {
+                let segments = path.segments.iter().skip_while(|segment| {
+                    ["self", "super", "crate"]
+                        .iter()
+                        .any(|name| is_named(&segment.identifier, name))
+                });
+                self.path(&ast::Path {
+                    span: path.span,
+                    segments: segments.cloned().collect(),
+                })
+            }
+            // modules are tables of their items
+            [module, items @ .., ident] if self.modules.contains(&module.identifier.name) => {
+                let mut lua = module.identifier.name.to_string();
+                for item in items {
+                    lua.push('.');
+                    lua.push_str(&item.identifier.name.as_str());
+                }
+                let name = self.lua_name(ident.identifier.name);
+                self.push_str(&format!("{}.{}", lua, name));
+            }
+            // associated fns live in the type's table
+            [ty, ident] => {
+                let ty = self.type_name(&ty.identifier);
+                let name = self.lua_name(ident.identifier.name);
+                self.push_str(&format!("{}.{}", ty, name));
+            }
+            _ => self.unsupported(path.span, "path"),
+        }
+    }
+
+    /// The lua name of a type, resolving `Self` to the type of the impl.
+    fn type_name(&self, ident: &ast::Ident) -> ast::Name {
+        match self.impl_ty {
+            Some(ty) if is_named(ident, "Self") => ty,
+            _ => ident.name,
+        }
+    }
+
+    /// The variant `path` names, if it's a variant of one of our enums.
+    fn variant(&self, path: &ast::Path) -> Option<ast::Name> {
+        match path.segments.as_slice() {
+            [ty, variant] if self.enums.contains(&self.type_name(&ty.identifier)) => {
+                Some(variant.identifier.name)
+            }
+            _ => None,
+        }
+    }
+
+    fn struct_(&mut self, path: &ast::Path, fields: &[ast::Field], base: &Option<P<Expr>>) {
+        let (ty, tag) = match (path.segments.as_slice(), self.variant(path)) {
+            ([ty, _], Some(variant)) => (self.type_name(&ty.identifier), Some(variant)),
+            _ => (
+                self.type_name(&path.segments.last().unwrap().identifier),
+                None,
+            ),
+        };
+        let mut values = Vec::new();
+        if let Some(tag) = tag {
+            values.push(format!("tag = {}", lua_string(&tag.as_str())));
+        }
+        // the parser gives shorthand fields `x` their value `x` already
+        for field in fields {
+            let value = self.capture(|g| g.expr(&field.expr));
+            values.push(format!("{} = {}", field.ident.node.name, value));
+        }
+        // `..base` gives the rest of the fields, from a function taking it
+        // if it isn't a place we can look at more than once
+        let mut wrapped = None;
+        if let Some(base) = base {
+            let names = match self.structs.get(&ty) {
+                Some(names) => names.clone(),
+                None => return self.unsupported(base.span, "struct update of an unknown struct"),
+            };
+            let value = match &base.node {
+                // the type of `..Default::default()` is the struct's
+                ExprKind::Call(func, args) if is_default(func) && args.is_empty() => {
+                    default_of(&ty.as_str())
+                }
+                _ => self.capture(|g| g.expr(base)),
+            };
+            let base = if is_place(base) {
+                value
+            } else {
+                wrapped = Some(value);
+                "__rua_base".to_string()
+            };
+            for name in names {
+                if !fields.iter().any(|field| field.ident.node.name == name) {
+                    values.push(format!("{} = {}.{}", name, base, name));
+                }
+            }
+        }
+        // `m::Point { .. }` is in the module's table
+        let meta = if path.segments.len() == 1 || tag.is_some() {
+            ty.to_string()
+        } else {
+            self.capture(|g| g.path(path))
+        };
+        let table = format!("setmetatable({{{}}}, {})", values.join(", "), meta);
+        match wrapped {
+            Some(value) => self.push_str(&format!(
+                "(function(__rua_base) return {} end)({})",
+                table, value
+            )),
+            None => self.push_str(&table),
+        }
+    }
+
+    fn pat(&mut self, pat: &ast::Pat) {
+        // lua is a virgin without pattern matching so we assume pat is an ident.
+        // (we could add pattern matching if we're smart though)
+        match &pat.node {
+            // lua has no `mut` or `ref`, every binding mode is the same
+            PatKind::Ident(_, ident, _) => self.ident(&ident.node),
+            PatKind::Path(_, path) => self.path(path),
+            PatKind::Wild => self.push_str("_"),
+            PatKind::Tuple(pats, None) => {
+                for (i, pat) in pats.iter().enumerate() {
+                    self.pat(pat);
+                    if i + 1 != pats.len() {
+                        self.push_str(", ");
+                    }
+                }
+            }
+            _ => self.unsupported(pat.span, "pattern"),
+        }
+    }
+
+    fn stmt(&mut self, stmt: &ast::Stmt) {
+        match &stmt.node {
+            // `let x = value?` checks x itself
+            StmtKind::Local(local) => match local.init.as_ref().map(|init| &init.node) {
+                Some(ExprKind::Try(value)) if is_simple(&local.pat) => self.hoist_tries(value),
+                Some(_) => self.hoist_tries(local.init.as_ref().unwrap()),
+                None => {}
+            },
+            StmtKind::Expr(expr) | StmtKind::Semi(expr) => self.hoist_tries(expr),
+            _ => {}
+        }
+        self.indent();
+        let start = self.buf.len();
+        match &stmt.node {
+            StmtKind::Item(item) => {
+                self.item(item);
+                // items end with a blank line, statements don't
+                let len = self.buf.trim_end_matches('\n').len();
+                self.buf.truncate(len);
+            }
+            StmtKind::Expr(_) | StmtKind::Semi(_) => self.stmt_expr(stmt),
+            StmtKind::Mac(mac) => self.mac(&mac.0),
+            StmtKind::Local(local) => self.local(local),
+        }
+        // a statement starting with ( would be parsed as a call on the previous line
+        if self.buf[start..].starts_with('(') {
+            self.buf.insert(start, ';');
+        }
+        self.push_str("\n");
+    }
+
+    /// Emits `expr` where a `ty` is expected, `.into()` a type with a From
+    /// impl calls its from.
+    fn converted(&mut self, expr: &ast::Expr, ty: Option<ast::Name>) {
+        let ty = match ty {
+            Some(ty) if ty == "Self" => self.impl_ty,
+            ty => ty,
+        };
+        match (into_value(expr), ty) {
+            (Some(value), Some(ty)) if self.froms.contains(&ty) => {
+                self.push_str(&format!("{}.from(", ty));
+                self.expr(value);
+                self.push_str(")");
+            }
+            _ => self.expr(expr),
+        }
+    }
+
+    /// Whether `?` can return nil from where we are, IIFEs would only
+    /// return from themselves.
+    fn can_try(&self) -> bool {
+        self.returns.is_some_and(|ty| ty == "Option") && !self.in_iife
+    }
+
+    /// Evaluates the `?`s in `expr` into locals before it, returning nil
+    /// when they're None. Branches and loops are left to their own
+    /// statements since they don't always run. What runs before a `?` in
+    /// rust goes in a local before it too, so it still runs first.
+    fn hoist_tries(&mut self, expr: &ast::Expr) {
+        if !self.can_try() {
+            return;
+        }
+        for hoisted in tries(expr) {
+            let name = format!("__rua_t{}", self.tries.len() + 1);
+            match &hoisted.node {
+                ExprKind::Try(value) if self.is_string_write(value) => continue,
+                ExprKind::Try(value) => {
+                    self.indent();
+                    self.push_str(&format!("local {} = ", name));
+                    self.expr(value);
+                    self.push_str("\n");
+                    self.lines(&format!("if {} == nil then return nil end", name));
+                }
+                _ => {
+                    self.indent();
+                    self.push_str(&format!("local {} = ", name));
+                    self.expr(hoisted);
+                    self.push_str("\n");
+                }
+            }
+            self.tries.insert(hoisted.span, name);
+        }
+    }
+
+    fn local(&mut self, local: &ast::Local) {
+        // let <pat>:<ty> = <expr>
+        if let PatKind::Ident(_, ident, _) = &local.pat.node {
+            if is_string_local(local) {
+                self.strings.insert(ident.node.name);
+            } else {
+                self.strings.remove(&ident.node.name);
+            }
+            let char = local.ty.as_ref().is_some_and(|ty| is_char_ty(ty))
+                || local.init.as_ref().is_some_and(|init| self.is_char(init));
+            self.track_char(ident.node.name, char);
+            let int = match &local.ty {
+                Some(ty) => int_ty(ty),
+                None => local.init.as_ref().and_then(|init| self.int_of(init)),
+            };
+            self.track_int(ident.node.name, int);
+            let integral = int.is_some()
+                || local
+                    .init
+                    .as_ref()
+                    .is_some_and(|init| self.is_integral(init));
+            track(&mut self.integrals, ident.node.name, integral);
+            let set = local.ty.as_ref().is_some_and(|ty| is_set_ty(ty))
+                || local.init.as_ref().is_some_and(|init| is_new_set(init));
+            track(&mut self.sets, ident.node.name, set);
+            let map = local.ty.as_ref().is_some_and(|ty| is_map_ty(ty))
+                || local.init.as_ref().is_some_and(|init| is_new_map(init));
+            track(&mut self.maps, ident.node.name, map);
+            let slice = local.ty.as_ref().is_some_and(|ty| is_slice_ty(ty))
+                || local.init.as_ref().is_some_and(|init| {
+                    matches!(init.node, ExprKind::Array(_) | ExprKind::Repeat(..))
+                });
+            track(&mut self.slices, ident.node.name, slice);
+            let result = local.ty.as_ref().is_some_and(|ty| is_result_ty(ty))
+                || local.init.as_ref().is_some_and(|init| self.is_result(init));
+            track(&mut self.results, ident.node.name, result);
+        }
+        let result = match &local.pat.node {
+            PatKind::Ident(_, ident, None) if self.results.contains(&ident.node.name) => {
+                Some(ident.node.name)
+            }
+            _ => None,
+        };
+        self.integer_parse = match (&local.ty, &local.init) {
+            (Some(ty), Some(init)) if int_ty(ty).is_some() => {
+                parse_of(init).map(|parse| parse.span)
+            }
+            _ => None,
+        };
+        match &local.init {
+            Some(init) if !is_simple(&local.pat) && let_else(init).is_none() => {
+                return self.destructure(&local.pat, init);
+            }
+            Some(init) if is_branching(init) && let_else(init).is_none() => {
+                // the branches can't see the local they assign, so they
+                // can't use a name it shadows or have locals hiding it
+                if let PatKind::Ident(_, ident, None) = &local.pat.node {
+                    if !mentions(init, ident.node.name) {
+                        let mut name = self.capture(|g| g.pat(&local.pat));
+                        if let Some(result) = result {
+                            name = format!("{}, {}", name, result_err(result));
+                        }
+                        return self.local_branches(name, init, true);
+                    }
+                    // an IIFE would only return from itself, so the branches
+                    // assign a temporary the local is made from instead
+                    if escapes(init) {
+                        let mut temp = format!("__rua_let_{}", ident.node.name);
+                        if let Some(result) = result {
+                            temp = format!("{}, __rua_let_err_{}", temp, result);
+                        }
+                        self.local_branches(temp.clone(), init, true);
+                        self.push_str("\n");
+                        self.indent();
+                        self.push_str("local ");
+                        self.pat(&local.pat);
+                        if let Some(result) = result {
+                            self.push_str(&format!(", {}", result_err(result)));
+                        }
+                        return self.push_str(&format!(" = {}", temp));
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.push_str("local ");
+        self.pat(&local.pat);
+        if let Some(result) = result {
+            self.push_str(&format!(", {}", result_err(result)));
+        }
+        if let Some(init) = &local.init {
+            self.push_str(" = ");
+            match let_else(init) {
+                Some((value, els)) => {
+                    self.expr(value);
+                    self.push_str("\n");
+                    self.indent();
+                    self.push_str("if ");
+                    self.pat(&local.pat);
+                    self.push_str(" == nil then\n");
+                    self.block(els);
+                    self.end();
+                }
+                None => match (&local.pat.node, &init.node) {
+                    (PatKind::Ident(..), ExprKind::Try(value)) if self.can_try() => {
+                        self.expr(value);
+                        self.push_str("\n");
+                        self.indent();
+                        self.push_str("if ");
+                        self.pat(&local.pat);
+                        self.push_str(" == nil then return nil end");
+                    }
+                    (PatKind::Tuple(..), ExprKind::Tup(exprs)) => self.exprs(exprs),
+                    // fns return tuples as multiple values
+                    (PatKind::Tuple(..), ExprKind::Call(..))
+                    | (PatKind::Tuple(..), ExprKind::MethodCall(..)) => self.expr(init),
+                    (PatKind::Tuple(..), _) => {
+                        self.push_str("table.unpack(");
+                        self.expr(init);
+                        self.push_str(")");
+                    }
+                    // the type says what's collected
+                    (_, ExprKind::MethodCall(method, _, args))
+                        if is_named(&method.node, "collect")
+                            && args.len() == 1
+                            && is_chain(&args[0])
+                            && local.ty.as_ref().is_some_and(|ty| is_string_ty(ty)) =>
+                    {
+                        self.collect(&args[0], true)
+                    }
+                    (_, ExprKind::Call(..) | ExprKind::MethodCall(..))
+                        if self.returns_tuple(init) =>
+                    {
+                        self.value(init)
+                    }
+                    // the type says what default this is
+                    (_, ExprKind::Call(func, args)) if is_default(func) && args.is_empty() => {
+                        match &local.ty {
+                            Some(ty) => self.push_str(&default_value(ty)),
+                            None => self.unsupported(init.span, "Default::default without a type"),
+                        }
+                    }
+                    _ if result.is_some() => self.result_value(init),
+                    _ => self.converted(init, local.ty.as_ref().and_then(|ty| ty_name(ty))),
+                },
+            }
+        }
+    }
+
+    /// Emits `let pat = init` for patterns that take values out of tables.
+    fn destructure(&mut self, pat: &ast::Pat, init: &ast::Expr) {
+        let value = if is_place(init) {
+            self.capture(|g| g.expr(init))
+        } else {
+            self.push_str("local __rua_v = ");
+            match &init.node {
+                // fns return tuples as multiple values
+                ExprKind::Call(..) | ExprKind::MethodCall(..) if is_tuple(pat) => {
+                    self.push_str("{");
+                    self.expr(init);
+                    self.push_str("}");
+                }
+                _ => self.expr(init),
+            }
+            self.push_str("\n");
+            self.indent();
+            "__rua_v".to_string()
+        };
+        let mut binds = Vec::new();
+        self.pat_bindings(pat, &value, &mut binds);
+        if binds.is_empty() {
+            return self.push_str(&format!("local _ = {}", value));
+        }
+        let (names, values): (Vec<String>, Vec<String>) = binds
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .unzip();
+        self.push_str(&format!(
+            "local {} = {}",
+            names.join(", "),
+            values.join(", ")
+        ));
+    }
+
+    fn stmt_expr(&mut self, stmt: &ast::Stmt) {
+        match &stmt.node {
+            StmtKind::Expr(expr) => self.expr_stmt(expr),
+            // just an expr with a trailing semicolon
+            StmtKind::Semi(expr) => self.expr_stmt(expr),
+            _ => panic!("not an expression statement: {:?}", stmt),
+        }
+    }
+
+    fn item(&mut self, item: &ast::Item) {
+        if !self.cfg(&item.attrs) {
+            return;
+        }
+        match &item.node {
+            ItemKind::Fn(..) if is_test(&item.attrs) && !self.emit_tests => {}
+            ItemKind::Fn(decl, _, _, _, _, block) => {
+                if is_test(&item.attrs) {
+                    let should_panic = item
+                        .attrs
+                        .iter()
+                        .any(|attr| attr.check_name("should_panic"));
+                    let mut path = self.mod_path.clone();
+                    path.push(item.ident.name);
+                    self.tests.push((path, should_panic));
+                }
+                let name = self.lua_name(item.ident.name);
+                let varargs = self.varargs.contains(&item.ident.name);
+                self.fn_(&name.as_str(), &decl.inputs, varargs, decl, block)
+            }
+
+            ItemKind::Const(_, expr) | ItemKind::Static(_, _, expr) if is_branching(expr) => {
+                let declare = !self.is_declared(item.ident.name) && !is_static_mut(item);
+                self.local_branches(item.ident.name.to_string(), expr, declare);
+                self.push_str("\n");
+            }
+            ItemKind::Const(_, expr) | ItemKind::Static(_, _, expr) => {
+                // mutable statics are globals, so every fn sees the same one
+                if !self.is_declared(item.ident.name) && !is_static_mut(item) {
+                    self.push_str("local ");
+                }
+                self.push_str(&format!("{} = ", item.ident.name));
+                self.expr(expr);
+                self.push_str("\n");
+            }
+
+            // lua has no types to alias
+            ItemKind::Ty(..) => {}
+
+            // the parts of std we support are built into lua
+            _ if is_std_use(item) => {}
+            // lua sees the items of enclosing modules already
+            _ if is_super_use(item) => {}
+
+            ItemKind::Mod(module) => self.mod_(item.ident.name, module),
+
+            // structs are tables their instances get their methods from
+            ItemKind::Struct(data, _) => {
+                let name = item.ident.name;
+                self.push_str(&format!("{} = {{}}\n{}.__index = {}\n", name, name, name));
+                // calling a tuple struct makes an instance, like its constructor
+                if data.is_tuple() {
+                    self.push_str(&format!(
+                        "setmetatable({}, {{__call = function(_, ...)\n  return setmetatable({{...}}, {})\nend}})\n",
+                        name, name
+                    ));
+                }
+                if derives(&item.attrs, "Debug") {
+                    self.derive_debug(name, data);
+                }
+                if derives(&item.attrs, "Default") {
+                    self.derive_default(name, data);
+                }
+                if derives(&item.attrs, "Clone") {
+                    self.helper(prelude::CLONE);
+                    self.push_str(&format!(
+                        "function {}:clone()\n  return __rua_clone(self)\nend\n",
+                        name
+                    ));
+                }
+                self.push_str("\n");
+            }
+
+            ItemKind::Enum(def, _) => self.enum_(item.ident.name, def),
+
+            ItemKind::Trait(.., items) => self.trait_(item.ident.name, items),
+
+            // trait impls put their methods in the type's table like any other
+            ItemKind::Impl(_, _, _, _, trait_ref, ty, items) => {
+                let ty = match &ty.node {
+                    TyKind::Path(None, path) => path.segments.last().unwrap().identifier.name,
+                    _ => return self.unsupported(ty.span, "impl"),
+                };
+                let trait_name = trait_ref
+                    .as_ref()
+                    .map(|trait_ref| trait_ref.path.segments.last().unwrap().identifier.name);
+                if let Some(provided) = trait_name.and_then(|name| self.traits.get(&name)) {
+                    for method in provided.clone() {
+                        if !items.iter().any(|item| item.ident.name == method) {
+                            let method = self.lua_name(method);
+                            self.inherited.push(format!(
+                                "{}.{} = {}.{}",
+                                ty,
+                                method,
+                                trait_name.unwrap(),
+                                method
+                            ));
+                        }
+                    }
+                }
+                let outer = self.impl_ty.replace(ty);
+                for item in items {
+                    match &item.node {
+                        _ if !self.cfg(&item.attrs) => {}
+                        ast::ImplItemKind::Method(sig, block) => {
+                            let decl = &sig.decl;
+                            let method = self.lua_name(item.ident.name);
+                            let varargs = self.varargs.contains(&item.ident.name);
+                            if decl.has_self() {
+                                let name = format!("{}:{}", ty, method);
+                                self.fn_(&name, &decl.inputs[1..], varargs, decl, block);
+                            } else {
+                                let name = format!("{}.{}", ty, method);
+                                self.fn_(&name, &decl.inputs, varargs, decl, block);
+                            }
+                        }
+                        ast::ImplItemKind::Const(_, expr) => {
+                            self.push_str(&format!("{}.{} = ", ty, item.ident.name));
+                            self.expr(expr);
+                            self.push_str("\n");
+                        }
+                        // lua has no types to name
+                        ast::ImplItemKind::Type(_) => {}
+                        ast::ImplItemKind::Macro(_) => self.report(item.span, "impl item"),
+                    }
+                }
+                self.impl_ty = outer;
+            }
+
+            _ => self.unsupported(item.span, "item"),
+        }
+    }
+
+    /// Modules are tables of their items, which are locals in a block of
+    /// their own so they see each other without the module in front.
+    fn mod_(&mut self, name: ast::Name, module: &ast::Mod) {
+        self.push_str(&format!("{} = {{}}\n", name));
+        let declared = mem::take(&mut self.declared);
+        let inherited = mem::take(&mut self.inherited);
+        self.mod_path.push(name);
+        // lua has no privacy, the table gets all of them, tests too
+        let names = self.declarations(module, &mut Vec::new());
+        let body = self.capture(|g| {
+            if !names.is_empty() {
+                g.push_str(&format!("local {}\n", names.join(", ")));
+            }
+            for item in &module.items {
+                g.comments_before(item.span.lo, true);
+                g.item(item);
+            }
+            // types in here are only in scope in here
+            for inherited in mem::take(&mut g.inherited) {
+                g.push_str(&inherited);
+                g.push_str("\n");
+            }
+            let buf = g.buf.trim_end().len();
+            g.buf.truncate(buf);
+            g.push_str("\n\n");
+            for item in names {
+                g.push_str(&format!("{}.{} = {}\n", name, item, item));
+            }
+        });
+        self.mod_path.pop();
+        self.inherited = inherited;
+        self.declared = declared;
+        self.push_str("do\n");
+        self.push_str(&indented(&body));
+        self.push_str("end\n\n");
+    }
+
+    /// Traits are a table of the methods they provide a default for, impls
+    /// not overriding one get it from there.
+    fn trait_(&mut self, name: ast::Name, items: &[ast::TraitItem]) {
+        self.push_str(&format!("{} = {{}}\n\n", name));
+        let outer = self.impl_ty.replace(name);
+        for item in items {
+            match &item.node {
+                _ if !self.cfg(&item.attrs) => {}
+                ast::TraitItemKind::Method(sig, Some(block)) => {
+                    let decl = &sig.decl;
+                    let method = self.lua_name(item.ident.name);
+                    let varargs = self.varargs.contains(&item.ident.name);
+                    if decl.has_self() {
+                        let name = format!("{}:{}", name, method);
+                        self.fn_(&name, &decl.inputs[1..], varargs, decl, block);
+                    } else {
+                        let path = format!("{}.{}", name, method);
+                        self.fn_(&path, &decl.inputs, varargs, decl, block);
+                        // associated fns are also free fns, `Trait_fn`
+                        let alias = associated_alias(name, item).unwrap();
+                        self.push_str(&format!("{} = {}\n\n", alias, path));
+                    }
+                }
+                // required methods are up to the impls
+                ast::TraitItemKind::Method(_, None) | ast::TraitItemKind::Type(..) => {}
+                _ => self.unsupported(item.span, "trait item"),
+            }
+        }
+        self.impl_ty = outer;
+    }
+
+    /// Enums are tables tagged with their variant. Unit variants are a
+    /// single shared value, tuple variants hold their fields in order and
+    /// struct variants are made with the usual struct syntax. Each variant
+    /// gets an `is_<variant>` method testing for it.
+    fn enum_(&mut self, name: ast::Name, def: &ast::EnumDef) {
+        self.push_str(&format!("{} = {{}}\n{}.__index = {}\n", name, name, name));
+        for variant in &def.variants {
+            let variant = &variant.node;
+            let tag = lua_string(&variant.name.name.as_str());
+            match &variant.data {
+                ast::VariantData::Unit(_) => self.push_str(&format!(
+                    "{}.{} = setmetatable({{tag = {}}}, {})\n",
+                    name, variant.name, tag, name
+                )),
+                ast::VariantData::Tuple(..) => self.push_str(&format!(
+                    "function {}.{}(...)\n  return setmetatable({{tag = {}, ...}}, {})\nend\n",
+                    name, variant.name, tag, name
+                )),
+                ast::VariantData::Struct(..) => {}
+            }
+        }
+        for variant in &def.variants {
+            let variant = &variant.node.name;
+            self.push_str(&format!(
+                "function {}:is_{}()\n  return self.tag == {}\nend\n",
+                name,
+                snake_case(&variant.name.as_str()),
+                lua_string(&variant.name.as_str())
+            ));
+        }
+        self.push_str("\n");
+    }
+
+    /// Gives instances of a struct deriving Debug a `__tostring` that
+    /// formats them like rust's `{:?}`.
+    fn derive_debug(&mut self, name: ast::Name, data: &ast::VariantData) {
+        self.helper(prelude::DEBUG_FMT);
+        let fields = data.fields();
+        let (open, close) = if data.is_tuple() {
+            ("(", ")")
+        } else {
+            (" { ", " }")
+        };
+        let mut body = Vec::new();
+        for (i, field) in fields.iter().enumerate() {
+            let sep = if i == 0 {
+                format!("{}{}", name, open)
+            } else {
+                ", ".to_string()
+            };
+            body.push(match field.ident {
+                Some(ident) => format!(
+                    "'{}{}: ' .. __rua_debug_fmt(self.{})",
+                    sep, ident.name, ident.name
+                ),
+                None => format!("'{}' .. __rua_debug_fmt(self[{}])", sep, i + 1),
+            });
+        }
+        body.push(lua_string(close));
+        // like rust, structs without fields are just their name
+        let body = if fields.is_empty() {
+            lua_string(&name.as_str())
+        } else {
+            body.join(" .. ")
+        };
+        self.push_str(&format!(
+            "function {}:__tostring()\n  return {}\nend\n",
+            name, body
+        ));
+    }
+
+    /// Gives a struct deriving Default a `default` fn making an instance
+    /// with the zero value of each field.
+    fn derive_default(&mut self, name: ast::Name, data: &ast::VariantData) {
+        let fields: Vec<String> = data
+            .fields()
+            .iter()
+            .map(|field| match field.ident {
+                Some(ident) => format!("{} = {}", ident.name, default_value(&field.ty)),
+                None => default_value(&field.ty),
+            })
+            .collect();
+        self.push_str(&format!(
+            "function {}.default()\n  return setmetatable({{{}}}, {})\nend\n",
+            name,
+            fields.join(", "),
+            name
+        ));
+    }
+
+    fn fn_(
+        &mut self,
+        name: &str,
+        args: &[Arg],
+        varargs: bool,
+        decl: &ast::FnDecl,
+        block: &P<Block>,
+    ) {
+        self.push_str(&format!("function {}", name));
+        match args.split_last() {
+            // the last argument collects the varargs into a table
+            Some((rest, args)) if varargs => {
+                self.push_str("(");
+                for arg in args {
+                    self.pat(&arg.pat);
+                    self.push_str(", ");
+                }
+                self.push_str("...)\n");
+                self.curr_indent += 1;
+                self.indent();
+                self.push_str("local ");
+                self.pat(&rest.pat);
+                self.push_str(" = {...}");
+                self.curr_indent -= 1;
+            }
+            _ => self.args(args),
+        }
+        self.push_str("\n");
+        self.function(false, |g| {
+            g.returns = returns(decl);
+            match decl.output {
+                // only return the trailing expression of fns with a return type
+                ast::FunctionRetTy::Ty(_) => g.return_block(block),
+                ast::FunctionRetTy::Default(_) => {
+                    g.block(block);
+                    if g.explicit_nil && !block.stmts.last().is_some_and(diverges) {
+                        g.curr_indent += 1;
+                        g.indent();
+                        g.push_str("return nil\n");
+                        g.curr_indent -= 1;
+                    }
+                }
+            }
+        });
+        self.end();
+        self.push_str("\n\n");
+    }
+}
+
+fn is_static_mut(item: &ast::Item) -> bool {
+    matches!(item.node, ItemKind::Static(_, ast::Mutability::Mutable, _))
+}
+
+fn is_test(attrs: &[ast::Attribute]) -> bool {
+    attrs.iter().any(|attr| attr.check_name("test"))
+}
+
+/// Whether `attrs` has a `#[derive(...)]` of `name`.
+fn derives(attrs: &[ast::Attribute], name: &str) -> bool {
+    attrs
+        .iter()
+        .filter(|attr| attr.check_name("derive"))
+        .flat_map(|attr| attr.meta_item_list().unwrap_or_default())
+        .any(|derive| derive.check_name(name))
+}
+
+/// The arguments of the `#[lua(...)]` attributes in `attrs`.
+fn lua_attrs(attrs: &[ast::Attribute]) -> Vec<ast::NestedMetaItem> {
+    attrs
+        .iter()
+        .filter(|attr| attr.check_name("lua"))
+        .flat_map(|attr| attr.meta_item_list().unwrap_or_default())
+        .collect()
+}
+
+/// Standard library functions lua has under another name, whether called
+/// or passed around as values.
+const FUNCTIONS: &[(&str, &str)] = &[
+    ("std::process::exit", "os.exit"),
+    ("process::exit", "os.exit"),
+    ("std::cmp::max", "math.max"),
+    ("cmp::max", "math.max"),
+    ("std::cmp::min", "math.min"),
+    ("cmp::min", "math.min"),
+    ("std::time::Instant::now", "os.clock"),
+    ("time::Instant::now", "os.clock"),
+    ("Instant::now", "os.clock"),
+];
+
+/// Standard library constants and their values in lua, they can be written
+/// with any of the path in front, `std::cmp::Ordering::Less` is
+/// `Ordering::Less` too.
+const CONSTANTS: &[(&str, &str)] = &[
+    // Ordering is -1, 0 or 1, like __rua_cmp returns
+    ("Ordering::Less", "-1"),
+    ("Ordering::Equal", "0"),
+    ("Ordering::Greater", "1"),
+    // lua 5.3 numbers are 64 bit integers and doubles
+    ("f64::INFINITY", "math.huge"),
+    ("f32::INFINITY", "math.huge"),
+    ("f64::NEG_INFINITY", "-math.huge"),
+    ("f32::NEG_INFINITY", "-math.huge"),
+    ("f64::NAN", "(0 / 0)"),
+    ("f32::NAN", "(0 / 0)"),
+    ("f64::MAX", "1.7976931348623157e308"),
+    ("f64::MIN", "-1.7976931348623157e308"),
+    ("f64::EPSILON", "2.220446049250313e-16"),
+    ("consts::PI", "math.pi"),
+    ("consts::E", "2.718281828459045"),
+    ("i64::MAX", "math.maxinteger"),
+    ("i64::MIN", "math.mininteger"),
+    ("isize::MAX", "math.maxinteger"),
+    ("isize::MIN", "math.mininteger"),
+    ("i32::MAX", "2147483647"),
+    ("i32::MIN", "-2147483648"),
+    ("i16::MAX", "32767"),
+    ("i16::MIN", "-32768"),
+    ("i8::MAX", "127"),
+    ("i8::MIN", "-128"),
+    ("u32::MAX", "4294967295"),
+    ("u16::MAX", "65535"),
+    ("u8::MAX", "255"),
+    // doesn't fit, but it's usually a sentinel the largest integer works as
+    ("usize::MAX", "math.maxinteger"),
+    ("u32::MIN", "0"),
+    ("u16::MIN", "0"),
+    ("u8::MIN", "0"),
+    ("usize::MIN", "0"),
+];
+
+/// The lua value of the constant `path` names, if it's one of `CONSTANTS`.
+fn constant(path: &ast::Path) -> Option<&'static str> {
+    let name = path_name(path);
+    CONSTANTS
+        .iter()
+        .find(|(rust, _)| {
+            name == *rust || name.ends_with(rust) && name[..name.len() - rust.len()].ends_with("::")
+        })
+        .map(|(_, lua)| *lua)
+}
+
+/// Methods lua spells differently: the name, the number of arguments
+/// including the receiver, and the lua to emit, see `Generator::template`.
+const METHODS: &[(&str, usize, &str)] = &[
+    ("to_string", 1, "tostring({0})"),
+    ("to_uppercase", 1, "{0}:upper()"),
+    ("to_lowercase", 1, "{0}:lower()"),
+    ("to_ascii_uppercase", 1, "{0}:upper()"),
+    ("to_ascii_lowercase", 1, "{0}:lower()"),
+    // chars are one character strings, so lua's classes test them directly
+    ("is_ascii_digit", 1, "({0}:find('^%d') ~= nil)"),
+    ("is_ascii_alphabetic", 1, "({0}:find('^%a') ~= nil)"),
+    ("is_ascii_alphanumeric", 1, "({0}:find('^%w') ~= nil)"),
+    ("is_ascii_uppercase", 1, "({0}:find('^%u') ~= nil)"),
+    ("is_ascii_lowercase", 1, "({0}:find('^%l') ~= nil)"),
+    ("is_whitespace", 1, "({0}:find('^%s') ~= nil)"),
+    ("trim", 1, "{0}:match('^%s*(.-)%s*$')"),
+    ("trim_start", 1, "{0}:match('^%s*(.-)$')"),
+    ("trim_end", 1, "{0}:match('^(.-)%s*$')"),
+    ("contains", 2, "(string.find({0}, {1}, 1, true) ~= nil)"),
+    // maps keep their keys as they are, missing ones are nil
+    ("contains_key", 2, "({0}[{1}] ~= nil)"),
+    ("starts_with", 2, "({0}:sub(1, #{1}) == {1})"),
+    ("ends_with", 2, "({1} == '' or {0}:sub(-#{1}) == {1})"),
+    ("split", 2, "__rua_split({0}, {1})"),
+    // copies, so the result doesn't alias the source table
+    ("to_vec", 1, "__rua_copy({0})"),
+    ("clone", 1, "__rua_clone({0})"),
+    ("abs", 1, "math.abs({0})"),
+    ("min", 2, "math.min({0}, {1})"),
+    ("max", 2, "math.max({0}, {1})"),
+    ("sqrt", 1, "math.sqrt({0})"),
+    ("sort", 1, "table.sort({0})"),
+    ("sort_unstable", 1, "table.sort({0})"),
+    ("sort_by", 2, "__rua_sort_by({0}, {1})"),
+    ("sort_unstable_by", 2, "__rua_sort_by({0}, {1})"),
+    ("sort_by_key", 2, "__rua_sort_by_key({0}, {1})"),
+    ("sort_unstable_by_key", 2, "__rua_sort_by_key({0}, {1})"),
+    // Ordering is -1, 0 or 1
+    ("cmp", 2, "__rua_cmp({0}, {1})"),
+    ("partial_cmp", 2, "__rua_cmp({0}, {1})"),
+    ("is_lt", 1, "({0} < 0)"),
+    ("is_le", 1, "({0} <= 0)"),
+    ("is_gt", 1, "({0} > 0)"),
+    ("is_ge", 1, "({0} >= 0)"),
+    ("is_eq", 1, "({0} == 0)"),
+    ("is_ne", 1, "({0} ~= 0)"),
+    ("floor", 1, "math.floor({0})"),
+    ("ceil", 1, "math.ceil({0})"),
+    ("powi", 2, "{0} ^ {1}"),
+    ("powf", 2, "{0} ^ {1}"),
+    // Vec and VecDeque are both tables
+    ("len", 1, "#{0}"),
+    ("is_empty", 1, "(#{0} == 0)"),
+    ("push", 2, "table.insert({0}, {1})"),
+    ("pop", 1, "table.remove({0})"),
+    ("push_back", 2, "table.insert({0}, {1})"),
+    ("push_front", 2, "table.insert({0}, 1, {1})"),
+    ("pop_back", 1, "table.remove({0})"),
+    ("pop_front", 1, "table.remove({0}, 1)"),
+    ("front", 1, "{0}[1]"),
+    ("back", 1, "{0}[#{0}]"),
+    ("insert", 3, "table.insert({0}, {1:index}, {2})"),
+    ("remove", 2, "table.remove({0}, {1:index})"),
+    // None and Err are nil
+    ("unwrap", 1, "assert({0}, 'called unwrap on nil')"),
+    ("expect", 2, "assert({0}, {1})"),
+    ("unwrap_or", 2, "__rua_unwrap_or({0}, {1})"),
+    ("unwrap_or_else", 2, "__rua_or_else({0}, {1})"),
+    ("map_or", 3, "__rua_map_or({0}, {1}, {2})"),
+    ("map_or_else", 3, "__rua_map_or_else({0}, {1}, {2})"),
+    ("or", 2, "__rua_unwrap_or({0}, {1})"),
+    ("or_else", 2, "__rua_or_else({0}, {1})"),
+    ("and", 2, "__rua_and({0}, {1})"),
+    // Some(false) comes out as None
+    ("then_some", 2, "({0} and {1} or nil)"),
+    ("then", 2, "({0} and {1}() or nil)"),
+    // Instants are os.clock() times, Durations the seconds between them
+    ("elapsed", 1, "(os.clock() - {0})"),
+    ("duration_since", 2, "({0} - {1})"),
+    ("as_secs", 1, "math.floor({0})"),
+    ("as_millis", 1, "math.floor({0} * 1000)"),
+    ("as_micros", 1, "math.floor({0} * 1000000)"),
+    ("as_nanos", 1, "math.floor({0} * 1000000000)"),
+];
+
+/// Methods of sets, tables with `true` for each element.
+const SET_METHODS: &[(&str, usize, &str)] = &[
+    ("insert", 2, "{0}[{1}] = true"),
+    ("remove", 2, "{0}[{1}] = nil"),
+    ("contains", 2, "({0}[{1}] ~= nil)"),
+    ("is_empty", 1, "(next({0}) == nil)"),
+    ("len", 1, "__rua_count({0})"),
+    ("clear", 1, "for k in pairs({0}) do {0}[k] = nil end"),
+];
+
+/// Methods of maps, tables keyed by the keys. Missing keys are nil, which
+/// is None.
+const MAP_METHODS: &[(&str, usize, &str)] = &[
+    ("get", 2, "{0}[{1}]"),
+    ("get_mut", 2, "{0}[{1}]"),
+    ("contains_key", 2, "({0}[{1}] ~= nil)"),
+    ("is_empty", 1, "(next({0}) == nil)"),
+    ("len", 1, "__rua_count({0})"),
+];
+
+/// Map methods that are assignments, so only statements.
+const MAP_UPDATES: &[(&str, usize, &str)] = &[
+    ("insert", 3, "{0}[{1}] = {2}"),
+    ("remove", 2, "{0}[{1}] = nil"),
+    ("clear", 1, "for k in pairs({0}) do {0}[k] = nil end"),
+];
+
+/// The lua for a map update, see MAP_UPDATES.
+fn map_update(name: ast::Name, args: &[P<Expr>]) -> Option<&'static str> {
+    MAP_UPDATES
+        .iter()
+        .find(|(rust, len, _)| name == *rust && args.len() == *len)
+        .map(|(_, _, lua)| *lua)
+}
+
+/// Arithmetic that wraps or saturates instead of overflowing.
+const BOUNDED_OPS: &[&str] = &[
+    "wrapping_add",
+    "wrapping_sub",
+    "wrapping_mul",
+    "saturating_add",
+    "saturating_sub",
+    "saturating_mul",
+];
+
+/// Methods viewing their receiver as another type, which is the same
+/// value in lua.
+const IDENTITY_METHODS: &[&str] = &[
+    "as_str",
+    "as_ref",
+    "as_mut",
+    "as_slice",
+    "as_mut_slice",
+    "as_deref",
+    "borrow",
+    "borrow_mut",
+    "copied",
+    "into",
+    "as_secs_f64",
+    "as_secs_f32",
+];
+
+/// The result of parsing macro arguments, or the errors parsing them.
+fn parsed<T>(result: syntax::parse::PResult<T>) -> T {
+    result.unwrap_or_else(|mut e| {
+        e.emit();
+        panic!("errors while parsing macro arguments");
+    })
+}
+
+/// `CamelCase` as `camel_case`.
+fn snake_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i != 0 {
+            out.push('_');
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+/// A rust `// line` or `/* block */` comment as a lua one.
+fn lua_comment(comment: &str) -> String {
+    if let Some(block) = comment.strip_prefix("/*") {
+        let block = block.strip_suffix("*/").unwrap_or(block);
+        let level = bracket_level(block);
+        format!("--[{}[{}]{}]", level, block, level)
+    } else {
+        comment
+            .lines()
+            .map(|line| match line.trim_start().strip_prefix("//") {
+                Some(line) => format!("--{}", line),
+                None => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Whether the lua in `buf` ends by opening a block, where a blank line
+/// would look out of place.
+fn opens_block(buf: &str) -> bool {
+    let line = buf.trim_end_matches('\n').lines().last().unwrap_or("");
+    let line = line.trim();
+    line.ends_with(" then")
+        || line.ends_with(" do")
+        || line.ends_with(')') && line.contains("function")
+        || line == "else"
+        || line == "do"
+}
+
+/// Quotes `s` as a lua string literal.
+fn lua_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' => out.push_str("\\'"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // always use three digits so a following digit isn't swallowed
+            c if c.is_control() && (c as u32) < 0x80 => out.push_str(&format!("\\{:03}", c as u32)),
+            // other characters go in as utf-8, unless an editor wouldn't
+            // show them, lua 5.3 spells those like rust
+            c if is_invisible(c) => out.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+fn is_invisible(c: char) -> bool {
+    c.is_control()
+        || (c.is_whitespace() && c != ' ')
+        || matches!(c, '\u{200B}'..='\u{200F}' | '\u{2060}' | '\u{FEFF}')
+}
+
+/// `s` as a lua string, multi-line strings keep their lines in long brackets.
+fn lua_text(s: &str) -> String {
+    let multi_line = s.trim_end_matches('\n').contains('\n');
+    match long_string(s) {
+        Some(long) if multi_line => long,
+        _ => lua_string(s),
+    }
+}
+
+/// `s` as a lua long bracket string, `[[s]]`, with enough `=` between the
+/// brackets that `s` can't end it. None if `s` has characters lua would
+/// change in one, like `\r`.
+fn long_string(s: &str) -> Option<String> {
+    if s.chars().any(|c| c.is_control() && c != '\n' && c != '\t') {
+        return None;
+    }
+    let level = bracket_level(s);
+    // a newline right after the opening bracket is skipped
+    let newline = if s.starts_with('\n') { "\n" } else { "" };
+    Some(format!("[{}[{}{}]{}]", level, newline, s, level))
+}
+
+/// The `=`s a long bracket around `s` needs so `s` can't close it early.
+fn bracket_level(s: &str) -> String {
+    let mut level = String::new();
+    while format!("{}]", s).contains(&format!("]{}]", level)) {
+        level.push('=');
+    }
+    level
+}
+
+fn is_string_local(local: &ast::Local) -> bool {
+    let typed = local.ty.as_ref().is_some_and(|ty| is_string_ty(ty));
+    typed || local.init.as_ref().is_some_and(|init| is_string_expr(init))
+}
+
+fn is_string_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Path(None, path) => {
+            path.segments.len() == 1
+                && (is_named(&path.segments[0].identifier, "String")
+                    || is_named(&path.segments[0].identifier, "str"))
+        }
+        TyKind::Rptr(_, ty) => is_string_ty(&ty.ty),
+        _ => false,
+    }
+}
+
+/// Guesses if an expression makes a String, eg `String::new()` or `format!(..)`.
+fn is_string_expr(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Call(func, _) => match &func.node {
+            ExprKind::Path(None, path) => {
+                path.segments.len() == 2 && is_named(&path.segments[0].identifier, "String")
+            }
+            _ => false,
+        },
+        ExprKind::MethodCall(method, _, _) => {
+            is_named(&method.node, "to_string") || is_named(&method.node, "to_owned")
+        }
+        ExprKind::Mac(mac) => {
+            is_named(&mac.node.path.segments.last().unwrap().identifier, "format")
+        }
+        _ => false,
+    }
+}
+
+/// The value of an integer literal.
+fn int(expr: &ast::Expr) -> Option<u64> {
+    match &expr.node {
+        ExprKind::Lit(lit) => match lit.node {
+            LitKind::Int(n, _) if n.high64() == 0 => Some(n.low64()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_int(expr: &ast::Expr, n: u64) -> bool {
+    int(expr) == Some(n)
+}
+
+fn lua_op(op: ast::BinOpKind) -> &'static str {
+    use ast::BinOpKind::*;
+    match op {
+        Add => "+",
+        Sub => "-",
+        Mul => "*",
+        Div => "/",
+        Rem => "%",
+        And => "and",
+        Or => "or",
+        BitXor => "~",
+        BitAnd => "&",
+        BitOr => "|",
+        Shl => "<<",
+        Shr => ">>",
+        Eq => "==",
+        Lt => "<",
+        Le => "<=",
+        Ne => "~=",
+        Ge => ">=",
+        Gt => ">",
+    }
+}
+
+/// Lua's precedence for the operator `lua_op` maps `op` to, higher binds
+/// tighter. It lines up with rust's except that lua lets comparisons chain.
+fn precedence(op: ast::BinOpKind) -> u8 {
+    use ast::BinOpKind::*;
+    match op {
+        Or => 1,
+        And => 2,
+        Eq | Lt | Le | Ne | Ge | Gt => 3,
+        BitOr => 4,
+        BitXor => 5,
+        BitAnd => 6,
+        Shl | Shr => 7,
+        Add | Sub => 9,
+        Mul | Div | Rem => 10,
+    }
+}
+
+/// The lua for `Default::default()` of `ty`.
+fn default_value(ty: &ast::Ty) -> String {
+    match &ty.node {
+        TyKind::Path(None, path) => {
+            default_of(&path.segments.last().unwrap().identifier.name.as_str())
+        }
+        TyKind::Rptr(_, ty) => default_value(&ty.ty),
+        TyKind::Tup(tys) if tys.is_empty() => "nil".to_string(),
+        TyKind::Tup(tys) => {
+            let values: Vec<String> = tys.iter().map(|ty| default_value(ty)).collect();
+            format!("{{{}}}", values.join(", "))
+        }
+        TyKind::Array(elem, len) => match int(len) {
+            Some(len) => {
+                let values = vec![default_value(elem); len as usize];
+                format!("{{{}}}", values.join(", "))
+            }
+            None => "{}".to_string(),
+        },
+        _ => "{}".to_string(),
+    }
+}
+
+/// The lua for `Default::default()` of the type named `ty`, other structs
+/// have a `default` fn if they derive it.
+fn default_of(ty: &str) -> String {
+    match ty {
+        _ if is_number(ty) => "0",
+        "bool" => "false",
+        "String" | "str" => "''",
+        "char" => "'\\0'",
+        "Option" => "nil",
+        "Vec" | "VecDeque" | "HashMap" | "HashSet" | "BTreeMap" | "BTreeSet" => "{}",
+        _ => return format!("{}.default()", ty),
+    }
+    .to_string()
+}
+
+fn track(names: &mut HashSet<ast::Name>, name: ast::Name, tracked: bool) {
+    if tracked {
+        names.insert(name);
+    } else {
+        names.remove(&name);
+    }
+}
+
+fn is_set_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Path(None, path) => {
+            let name = path.segments.last().unwrap().identifier.name;
+            name == "HashSet" || name == "BTreeSet"
+        }
+        TyKind::Rptr(_, ty) => is_set_ty(&ty.ty),
+        _ => false,
+    }
+}
+
+fn is_slice_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Slice(_) | TyKind::Array(..) => true,
+        TyKind::Rptr(_, ty) => is_slice_ty(&ty.ty),
+        _ => false,
+    }
+}
+
+fn is_map_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Path(None, path) => {
+            let name = path.segments.last().unwrap().identifier.name;
+            name == "HashMap" || name == "BTreeMap"
+        }
+        TyKind::Rptr(_, ty) => is_map_ty(&ty.ty),
+        _ => false,
+    }
+}
+
+fn is_new_map(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Call(func, _) => match &func.node {
+            ExprKind::Path(None, path) => {
+                let name = path_name(path);
+                name.starts_with("HashMap::") || name.starts_with("BTreeMap::")
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_new_set(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Call(func, _) => match &func.node {
+            ExprKind::Path(None, path) => {
+                let name = path_name(path);
+                name.starts_with("HashSet::") || name.starts_with("BTreeSet::")
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The value of `value.into()`.
+fn into_value(expr: &ast::Expr) -> Option<&ast::Expr> {
+    match &expr.node {
+        ExprKind::MethodCall(method, _, args)
+            if is_named(&method.node, "into") && args.len() == 1 =>
+        {
+            Some(&args[0])
+        }
+        _ => None,
+    }
+}
+
+/// The name of `ty` without its path or generics.
+fn ty_name(ty: &ast::Ty) -> Option<ast::Name> {
+    match &ty.node {
+        TyKind::Path(None, path) => Some(path.segments.last().unwrap().identifier.name),
+        _ => None,
+    }
+}
+
+/// The name of `ty` if it's an integer type.
+fn int_ty(ty: &ast::Ty) -> Option<ast::Name> {
+    match &ty.node {
+        TyKind::Path(None, path) if path.segments.len() == 1 => {
+            let name = path.segments[0].identifier.name;
+            int_bits(name).map(|_| name)
+        }
+        TyKind::Rptr(_, ty) => int_ty(&ty.ty),
+        _ => None,
+    }
+}
+
+/// Whether an integer type is signed, and how many bits it has.
+fn int_bits(ty: ast::Name) -> Option<(bool, u32)> {
+    let ty = ty.as_str();
+    let signed = ty.starts_with('i');
+    let bits = match ty.get(1..)? {
+        "8" => 8,
+        "16" => 16,
+        "32" => 32,
+        "64" | "size" => 64,
+        _ => return None,
+    };
+    (signed || ty.starts_with('u')).then_some((signed, bits))
+}
+
+fn is_char_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Path(None, path) => path_name(path) == "char",
+        _ => false,
+    }
+}
+
+/// `(x as u8) as char` is just `x as char`, lua numbers don't narrow.
+fn uncast(expr: &ast::Expr) -> &ast::Expr {
+    match &expr.node {
+        ExprKind::Cast(expr, ty) if !is_char_ty(ty) => uncast(expr),
+        ExprKind::Paren(expr) => uncast(expr),
+        _ => expr,
+    }
+}
+
+fn is_number(ty: &str) -> bool {
+    is_integer(ty) || ty == "f32" || ty == "f64"
+}
+
+fn is_integer(ty: &str) -> bool {
+    matches!(
+        ty,
+        "i8" | "i16"
+            | "i32"
+            | "i64"
+            | "i128"
+            | "isize"
+            | "u8"
+            | "u16"
+            | "u32"
+            | "u64"
+            | "u128"
+            | "usize"
+    )
+}
+
+/// Where `..` would sit in `precedence`.
+const CONCAT_PRECEDENCE: u8 = 8;
+
+/// Can `expr` be evaluated twice without anything observing it?
+fn is_pure(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Lit(_) => true,
+        ExprKind::Tup(exprs) if exprs.is_empty() => true,
+        ExprKind::Paren(expr) | ExprKind::AddrOf(_, expr) => is_pure(expr),
+        ExprKind::Unary(ast::UnOp::Neg, expr) => is_pure(expr),
+        ExprKind::Binary(_, lhs, rhs) => is_pure(lhs) && is_pure(rhs),
+        _ => is_place(expr),
+    }
+}
+
+fn is_arithmetic(op: ast::BinOpKind) -> bool {
+    use ast::BinOpKind::*;
+    matches!(op, Add | Sub | Mul | Div | Rem)
+}
+
+/// A place is evaluated again cheaply and without side effects.
+fn is_place(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Path(None, path) => path.segments.len() == 1,
+        ExprKind::Field(expr, _) | ExprKind::TupField(expr, _) => is_place(expr),
+        ExprKind::Index(expr, index) => is_place(expr) && is_pure(index),
+        ExprKind::Unary(ast::UnOp::Deref, expr) => is_place(expr),
+        _ => false,
+    }
+}
+
+/// The string of `s.chars()`, and whether it's `s.chars().rev()`.
+fn chars_of(expr: &ast::Expr) -> Option<(&ast::Expr, bool)> {
+    match &expr.node {
+        ExprKind::MethodCall(method, _, args) if is_named(&method.node, "chars") => {
+            Some((&args[0], false))
+        }
+        ExprKind::MethodCall(method, _, args) if is_named(&method.node, "rev") => {
+            match chars_of(&args[0]) {
+                Some((s, false)) => Some((s, true)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The table a for loop walks, `&v` and `v.iter()` are just `v`.
+/// `a` and `b` in `a.iter().zip(b.iter())`, when both are places.
+fn zipped(expr: &ast::Expr) -> Option<(&ast::Expr, &ast::Expr)> {
+    match &expr.node {
+        ExprKind::MethodCall(method, _, args)
+            if is_named(&method.node, "zip") && args.len() == 2 =>
+        {
+            let (a, b) = (iterable(&args[0]), iterable(&args[1]));
+            if is_place(a) && is_place(b) {
+                Some((a, b))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn iterable(expr: &ast::Expr) -> &ast::Expr {
+    match &expr.node {
+        ExprKind::AddrOf(_, expr) => iterable(expr),
+        ExprKind::MethodCall(method, _, args)
+            if args.len() == 1
+                && (is_named(&method.node, "iter") || is_named(&method.node, "iter_mut")) =>
+        {
+            iterable(&args[0])
+        }
+        _ => expr,
+    }
+}
+
+/// `Trait_fn`, the free fn name of a trait's provided associated fn.
+fn associated_alias(trait_: ast::Name, item: &ast::TraitItem) -> Option<ast::Name> {
+    match &item.node {
+        ast::TraitItemKind::Method(sig, Some(_)) if !sig.decl.has_self() => Some(
+            ast::Name::intern(&format!("{}_{}", trait_, item.ident.name)),
+        ),
+        _ => None,
+    }
+}
+
+fn returns_tuple(decl: &ast::FnDecl) -> bool {
+    match &decl.output {
+        ast::FunctionRetTy::Ty(ty) => matches!(&ty.node, TyKind::Tup(tys) if !tys.is_empty()),
+        ast::FunctionRetTy::Default(_) => false,
+    }
+}
+
+fn returns_result(decl: &ast::FnDecl) -> bool {
+    match &decl.output {
+        ast::FunctionRetTy::Ty(ty) => is_result_ty(ty),
+        ast::FunctionRetTy::Default(_) => false,
+    }
+}
+
+fn is_ok_or_err(func: &ast::Expr) -> bool {
+    matches!(&func.node, ExprKind::Path(None, path) if ["Ok", "Err"].contains(&&*path_name(path)))
+}
+
+/// `e` in `Err(e)`.
+fn err_payload(expr: &ast::Expr) -> Option<&ast::Expr> {
+    match &expr.node {
+        ExprKind::Call(func, args) if args.len() == 1 => match &func.node {
+            ExprKind::Path(None, path) if path_name(path) == "Err" => Some(&args[0]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The local holding the error of the Result in the local `name`.
+fn result_err(name: ast::Name) -> String {
+    format!("__rua_err_{}", name)
+}
+
+/// `Result<T, E>`, or an alias like `io::Result<T>`.
+fn is_result_ty(ty: &ast::Ty) -> bool {
+    match &ty.node {
+        TyKind::Path(None, path) => path.segments.last().unwrap().identifier.name == "Result",
+        _ => false,
+    }
+}
+
+/// The bounds of a range with both ends, looking through parentheses.
+fn bounded_range(expr: &ast::Expr) -> Option<(&ast::Expr, &ast::Expr, ast::RangeLimits)> {
+    match &expr.node {
+        ExprKind::Paren(expr) => bounded_range(expr),
+        ExprKind::Range(Some(start), Some(end), limits) => Some((start, end, *limits)),
+        _ => None,
+    }
+}
+
+/// `&mut x` is just `x` in lua.
+fn strip_ref(expr: &ast::Expr) -> &ast::Expr {
+    match &expr.node {
+        ExprKind::AddrOf(_, expr) => expr,
+        _ => expr,
+    }
+}
+
+fn is_unit(expr: &ast::Expr) -> bool {
+    matches!(&expr.node, ExprKind::Tup(exprs) if exprs.is_empty())
+}
+
+fn is_neg(expr: &ast::Expr) -> bool {
+    match &expr.node {
+        ExprKind::Unary(ast::UnOp::Neg, _) => true,
+        ExprKind::Path(None, path) => constant(path).is_some_and(|lua| lua.starts_with('-')),
+        _ => false,
+    }
+}
+
+/// Whether `pat` is `Ok(pat)`, and the pattern inside, for `Ok` and `Err`.
+fn result_pat(pat: &ast::Pat) -> Option<(bool, &ast::Pat)> {
+    match &pat.node {
+        PatKind::TupleStruct(path, pats, None) if pats.len() == 1 => match &*path_name(path) {
+            "Ok" => Some((true, &pats[0])),
+            "Err" => Some((false, &pats[0])),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What `pat` matches against and how the error is tested, for Ok and
+/// Err patterns on a Result whose error is in `err`.
+fn result_arm<'p>(
+    pat: &'p ast::Pat,
+    value: &str,
+    err: Option<&str>,
+) -> (&'p ast::Pat, String, String) {
+    match (err, result_pat(pat)) {
+        (Some(err), Some((true, pat))) => (pat, value.to_string(), format!("{} == nil", err)),
+        (Some(err), Some((false, pat))) => (pat, err.to_string(), format!("{} ~= nil", err)),
+        _ => (pat, value.to_string(), String::new()),
+    }
+}
+
+/// Matches `if let Some(x) = value { x } else { return }`, how you write
+/// let-else without let-else. Gives the value and the else block.
+fn let_else(init: &ast::Expr) -> Option<(&ast::Expr, &P<Block>)> {
+    let (pat, value, block, els) = match &init.node {
+        ExprKind::IfLet(pat, value, block, Some(els)) => (pat, value, block, els),
+        _ => return None,
+    };
+    let binding = match &pat.node {
+        PatKind::TupleStruct(path, pats, None) if path_name(path) == "Some" && pats.len() == 1 => {
+            match &pats[0].node {
+                PatKind::Ident(_, ident, None) => ident.node.name,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let returns_binding = match block.stmts.as_slice() {
+        [ast::Stmt {
+            node: StmtKind::Expr(expr),
+            ..
+        }] => match &expr.node {
+            ExprKind::Path(None, path) => {
+                path.segments.len() == 1 && path.segments[0].identifier.name == binding
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+    match &els.node {
+        ExprKind::Block(els) if returns_binding && els.stmts.last().is_some_and(diverges) => {
+            Some((value, els))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `mac` is `assert!(false)`, with or without a message.
+fn is_assert_false(mac: &ast::Mac) -> bool {
+    let name = mac.node.path.segments.last().unwrap().identifier;
+    let mut tokens = mac.node.stream().trees();
+    let first = match tokens.next() {
+        Some(TokenTree::Token(_, token::Ident(ident))) => ident.name == "false",
+        _ => false,
+    };
+    is_named(&name, "assert")
+        && first
+        && matches!(
+            tokens.next(),
+            None | Some(TokenTree::Token(_, token::Comma))
+        )
+}
+
+/// Does control never make it past `stmt`?
+fn diverges(stmt: &ast::Stmt) -> bool {
+    let is_panic = |mac: &ast::Mac| {
+        let name = mac.node.path.segments.last().unwrap().identifier;
+        is_named(&name, "panic") || is_named(&name, "unreachable") || is_assert_false(mac)
+    };
+    match &stmt.node {
+        StmtKind::Expr(expr) | StmtKind::Semi(expr) => match &expr.node {
+            ExprKind::Ret(_) | ExprKind::Break(..) | ExprKind::Continue(_) => true,
+            // labeled loops might be blocks, which end without a break
+            ExprKind::Loop(block, None) => !breaks(block),
+            ExprKind::Mac(mac) => is_panic(mac),
+            _ => false,
+        },
+        StmtKind::Mac(mac) => is_panic(&mac.0),
+        _ => false,
+    }
+}
+
+/// The else of an if, unless it's empty and can be left out.
+fn non_empty(els: &Option<P<Expr>>) -> Option<&Expr> {
+    els.as_deref()
+        .filter(|els| !matches!(&els.node, ExprKind::Block(block) if block.stmts.is_empty()))
+}
+
+/// The lua for the `n` elements of the table `value` a tuple or slice
+/// pattern matches, those after a `..` at `rest` are counted from the end.
+fn elems(value: &str, n: usize, rest: Option<usize>) -> Vec<String> {
+    let rest = rest.unwrap_or(n);
+    (0..n)
+        .map(|i| match n - i {
+            _ if i < rest => format!("{}[{}]", value, i + 1),
+            1 => format!("{}[#{}]", value, value),
+            from_end => format!("{}[#{} - {}]", value, value, from_end - 1),
+        })
+        .collect()
+}
+
+/// Whether `let pat = ...` can bind `pat` directly, without taking it apart.
+fn is_simple(pat: &ast::Pat) -> bool {
+    match &pat.node {
+        PatKind::Ident(_, _, None) | PatKind::Wild => true,
+        PatKind::Tuple(pats, None) => pats
+            .iter()
+            .all(|pat| matches!(pat.node, PatKind::Ident(_, _, None) | PatKind::Wild)),
+        _ => false,
+    }
+}
+
+fn is_tuple(pat: &ast::Pat) -> bool {
+    matches!(pat.node, PatKind::Tuple(..))
+}
+
+fn returns(decl: &ast::FnDecl) -> Option<ast::Name> {
+    match &decl.output {
+        ast::FunctionRetTy::Ty(ty) => ty_name(ty),
+        ast::FunctionRetTy::Default(_) => None,
+    }
+}
+
+/// The `?`s in `expr` that always run and the operands with side effects
+/// that run before them, in the order they do.
+fn tries(expr: &ast::Expr) -> Vec<&ast::Expr> {
+    // `operands` is false to only look for the `?`s
+    struct Tries<'a> {
+        found: Vec<&'a ast::Expr>,
+        operands: bool,
+    }
+    impl<'a> Visitor<'a> for Tries<'a> {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            let operands = if self.operands {
+                operands(expr)
+            } else {
+                Vec::new()
+            };
+            let last_try = operands.iter().rposition(|operand| {
+                let mut tries = Tries {
+                    found: Vec::new(),
+                    operands: false,
+                };
+                tries.visit_expr(operand);
+                !tries.found.is_empty()
+            });
+            match &expr.node {
+                // `side() + g()?` calls side first
+                _ if last_try.is_some() => {
+                    for (i, operand) in operands.into_iter().enumerate() {
+                        self.visit_expr(operand);
+                        let hoist = !pure_once_hoisted(operand)
+                            && !matches!(operand.node, ExprKind::Path(..) | ExprKind::Closure(..));
+                        if i < last_try.unwrap() && hoist {
+                            self.found.push(operand);
+                        }
+                    }
+                }
+                ExprKind::Try(value) => {
+                    self.visit_expr(value);
+                    self.found.push(expr);
+                }
+                ExprKind::If(cond, ..) | ExprKind::While(cond, ..) => self.visit_expr(cond),
+                ExprKind::IfLet(_, value, ..)
+                | ExprKind::WhileLet(_, value, ..)
+                | ExprKind::Match(value, _)
+                | ExprKind::ForLoop(_, value, ..) => self.visit_expr(value),
+                // the right of && and || doesn't always run
+                ExprKind::Binary(op, lhs, _)
+                    if matches!(op.node, ast::BinOpKind::And | ast::BinOpKind::Or) =>
+                {
+                    self.visit_expr(lhs)
+                }
+                ExprKind::Block(..) | ExprKind::Loop(..) | ExprKind::Closure(..) => {}
+                _ => visit::walk_expr(self, expr),
+            }
+        }
+        fn visit_item(&mut self, _: &'a ast::Item) {}
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    // the `?`s it has are locals by the time it runs
+    fn pure_once_hoisted(expr: &ast::Expr) -> bool {
+        match &expr.node {
+            ExprKind::Try(_) => true,
+            ExprKind::Paren(expr) | ExprKind::AddrOf(_, expr) => pure_once_hoisted(expr),
+            ExprKind::Unary(ast::UnOp::Neg, expr) => pure_once_hoisted(expr),
+            ExprKind::Binary(_, lhs, rhs) => pure_once_hoisted(lhs) && pure_once_hoisted(rhs),
+            _ => is_pure(expr),
+        }
+    }
+    let mut tries = Tries {
+        found: Vec::new(),
+        operands: true,
+    };
+    tries.visit_expr(expr);
+    tries.found
+}
+
+/// The operands of `expr` rust evaluates one after the other.
+fn operands(expr: &ast::Expr) -> Vec<&ast::Expr> {
+    match &expr.node {
+        ExprKind::Binary(op, lhs, rhs)
+            if !matches!(op.node, ast::BinOpKind::And | ast::BinOpKind::Or) =>
+        {
+            vec![lhs, rhs]
+        }
+        ExprKind::Index(lhs, rhs) => vec![lhs, rhs],
+        ExprKind::Call(func, args) => std::iter::once(func)
+            .chain(args)
+            .map(|arg| &**arg)
+            .collect(),
+        ExprKind::MethodCall(_, _, exprs) | ExprKind::Tup(exprs) | ExprKind::Array(exprs) => {
+            exprs.iter().map(|expr| &**expr).collect()
+        }
+        ExprKind::Struct(_, fields, base) => {
+            let fields = fields.iter().map(|field| &*field.expr);
+            fields.chain(base.as_deref()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// `{ let pat = value; body }`, what a match with one arm binding `pat` does.
+fn let_block(pat: &P<ast::Pat>, value: &ast::Expr, body: &ast::Expr) -> Expr {
+    let local = ast::Local {
+        pat: pat.clone(),
+        ty: None,
+        init: Some(P(value.clone())),
+        id: ast::DUMMY_NODE_ID,
+        span: pat.span,
+        attrs: Vec::new().into(),
+    };
+    let mut stmts = vec![ast::Stmt {
+        id: ast::DUMMY_NODE_ID,
+        node: StmtKind::Local(P(local)),
+        span: pat.span,
+    }];
+    // `_` doesn't bind, only values that do something need evaluating
+    if matches!(pat.node, PatKind::Wild) && is_pure(value) {
+        stmts.clear();
+    }
+    match &body.node {
+        ExprKind::Block(block) => stmts.extend(block.stmts.iter().cloned()),
+        _ => stmts.push(ast::Stmt {
+            id: ast::DUMMY_NODE_ID,
+            node: StmtKind::Expr(P(body.clone())),
+            span: body.span,
+        }),
+    }
+    let block = Block {
+        stmts,
+        id: ast::DUMMY_NODE_ID,
+        rules: ast::BlockCheckMode::Default,
+        span: body.span,
+    };
+    Expr {
+        id: ast::DUMMY_NODE_ID,
+        node: ExprKind::Block(P(block)),
+        span: body.span,
+        attrs: Vec::new().into(),
+    }
+}
+
+/// The names bound or declared in `module` that lua can't have, its names
+/// are ascii and can't be keywords. Everything else is emitted exactly as
+/// it's spelled in rust.
+fn unspellable(module: &ast::Mod) -> Vec<(Span, ast::Ident)> {
+    struct Unspellable(Vec<(Span, ast::Ident)>);
+    impl Unspellable {
+        fn check(&mut self, span: Span, ident: ast::Ident) {
+            let name = ident.name.as_str();
+            if !name.is_ascii() || LUA_KEYWORDS.contains(&&*name) {
+                self.0.push((span, ident));
+            }
+        }
+    }
+    impl<'a> Visitor<'a> for Unspellable {
+        fn visit_item(&mut self, item: &'a ast::Item) {
+            if !matches!(item.node, ItemKind::Use(..) | ItemKind::ExternCrate(..)) {
+                self.check(item.span, item.ident);
+            }
+            visit::walk_item(self, item)
+        }
+        fn visit_trait_item(&mut self, item: &'a ast::TraitItem) {
+            self.check(item.span, item.ident);
+            visit::walk_trait_item(self, item)
+        }
+        fn visit_impl_item(&mut self, item: &'a ast::ImplItem) {
+            self.check(item.span, item.ident);
+            visit::walk_impl_item(self, item)
+        }
+        fn visit_struct_field(&mut self, field: &'a ast::StructField) {
+            if let Some(ident) = field.ident {
+                self.check(field.span, ident);
+            }
+            visit::walk_struct_field(self, field)
+        }
+        fn visit_pat(&mut self, pat: &'a ast::Pat) {
+            if let PatKind::Ident(_, ident, _) = &pat.node {
+                self.check(ident.span, ident.node);
+            }
+            visit::walk_pat(self, pat)
+        }
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    let mut unspellable = Unspellable(Vec::new());
+    visit::walk_mod(&mut unspellable, module);
+    unspellable.0
+}
+
+/// The top level items of `module` named like one of `LUA_GLOBALS`.
+fn clobbered_globals(module: &ast::Mod) -> Vec<(Span, ast::Ident)> {
+    let items = module.items.iter().filter(|item| {
+        !matches!(
+            item.node,
+            ItemKind::Use(..) | ItemKind::ExternCrate(..) | ItemKind::Impl(..)
+        )
+    });
+    items
+        .filter(|item| LUA_GLOBALS.contains(&&*item.ident.name.as_str()))
+        .map(|item| (item.span, item.ident))
+        .collect()
+}
+
+/// Lua keywords that aren't rust keywords too.
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "elseif", "end", "function", "goto", "local", "nil", "not", "or", "repeat", "then",
+    "until",
+];
+
+/// Lua's globals the output and the prelude use, top level items can't
+/// have their names without breaking them.
+const LUA_GLOBALS: &[&str] = &[
+    "assert",
+    "error",
+    "ipairs",
+    "math",
+    "next",
+    "os",
+    "pairs",
+    "pcall",
+    "print",
+    "select",
+    "setmetatable",
+    "string",
+    "table",
+    "tonumber",
+    "tostring",
+    "type",
+    "utf8",
+];
+
+/// `'a` as a lua label.
+fn lua_label(label: ast::Name) -> String {
+    label.as_str().trim_start_matches('\'').to_string()
+}
+
+/// The label `continue` jumps to in the loop `depth` loops deep.
+fn continue_label(depth: usize) -> String {
+    if depth == 1 {
+        "continue".to_string()
+    } else {
+        format!("continue{}", depth)
+    }
+}
+
+/// Whether a loop body breaks out of it, labeled breaks might be to it from
+/// loops inside.
+fn breaks(block: &Block) -> bool {
+    struct Breaks(bool, usize);
+    impl<'a> Visitor<'a> for Breaks {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            match &expr.node {
+                ExprKind::Break(None, _) if self.1 == 0 => self.0 = true,
+                ExprKind::Break(Some(_), _) => self.0 = true,
+                ExprKind::Loop(..)
+                | ExprKind::ForLoop(..)
+                | ExprKind::While(..)
+                | ExprKind::WhileLet(..) => {
+                    self.1 += 1;
+                    visit::walk_expr(self, expr);
+                    self.1 -= 1;
+                }
+                ExprKind::Closure(..) => {}
+                _ => visit::walk_expr(self, expr),
+            }
+        }
+        fn visit_item(&mut self, _: &'a ast::Item) {}
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    let mut breaks = Breaks(false, 0);
+    visit::walk_block(&mut breaks, block);
+    breaks.0
+}
+
+/// Whether the body of the loop `label` breaks out of it from a loop
+/// inside it, which lua's break can't do.
+fn breaks_to(block: &Block, label: ast::Name) -> bool {
+    struct BreaksTo(bool, usize, ast::Name);
+    impl<'a> Visitor<'a> for BreaksTo {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            match &expr.node {
+                ExprKind::Break(Some(label), _) if self.1 > 0 && label.node.name == self.2 => {
+                    self.0 = true
+                }
+                ExprKind::Loop(..)
+                | ExprKind::ForLoop(..)
+                | ExprKind::While(..)
+                | ExprKind::WhileLet(..) => {
+                    self.1 += 1;
+                    visit::walk_expr(self, expr);
+                    self.1 -= 1;
+                }
+                ExprKind::Closure(..) => {}
+                _ => visit::walk_expr(self, expr),
+            }
+        }
+        fn visit_item(&mut self, _: &'a ast::Item) {}
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    let mut breaks = BreaksTo(false, 0, label);
+    visit::walk_block(&mut breaks, block);
+    breaks.0
+}
+
+/// Whether `expr` returns, or breaks or continues out of the loop it's in.
+fn escapes(expr: &ast::Expr) -> bool {
+    struct Escapes(bool, usize);
+    impl<'a> Visitor<'a> for Escapes {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            match &expr.node {
+                ExprKind::Ret(_) => self.0 = true,
+                ExprKind::Break(None, _) | ExprKind::Continue(None) if self.1 == 0 => self.0 = true,
+                ExprKind::Break(Some(_), _) | ExprKind::Continue(Some(_)) => self.0 = true,
+                ExprKind::Loop(..)
+                | ExprKind::ForLoop(..)
+                | ExprKind::While(..)
+                | ExprKind::WhileLet(..) => {
+                    self.1 += 1;
+                    visit::walk_expr(self, expr);
+                    self.1 -= 1;
+                }
+                ExprKind::Closure(..) => {}
+                _ => visit::walk_expr(self, expr),
+            }
+        }
+        fn visit_item(&mut self, _: &'a ast::Item) {}
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    let mut escapes = Escapes(false, 0);
+    escapes.visit_expr(expr);
+    escapes.0
+}
+
+/// Whether a loop body continues, ignoring closures and the continues of
+/// loops inside it that aren't to its `label`.
+fn continues(block: &Block, label: Option<ast::Name>) -> bool {
+    struct Continues(bool, usize, Option<ast::Name>);
+    impl<'a> Visitor<'a> for Continues {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            match &expr.node {
+                ExprKind::Continue(None) if self.1 == 0 => self.0 = true,
+                ExprKind::Continue(Some(label)) if Some(label.node.name) == self.2 => self.0 = true,
+                ExprKind::Loop(..)
+                | ExprKind::ForLoop(..)
+                | ExprKind::While(..)
+                | ExprKind::WhileLet(..) => {
+                    self.1 += 1;
+                    visit::walk_expr(self, expr);
+                    self.1 -= 1;
+                }
+                ExprKind::Closure(..) => {}
+                _ => visit::walk_expr(self, expr),
+            }
+        }
+        fn visit_item(&mut self, _: &'a ast::Item) {}
+        fn visit_mac(&mut self, _: &'a ast::Mac) {}
+    }
+    let mut continues = Continues(false, 0, label);
+    visit::walk_block(&mut continues, block);
+    continues.0
+}
+
+/// The `s.parse()` whose result `expr` is, through unwrapping it.
+fn parse_of(expr: &ast::Expr) -> Option<&ast::Expr> {
+    match &expr.node {
+        ExprKind::MethodCall(method, ..) if is_named(&method.node, "parse") => Some(expr),
+        ExprKind::MethodCall(method, _, args)
+            if ["unwrap", "expect", "unwrap_or", "unwrap_or_default"]
+                .contains(&&*method.node.name.as_str()) =>
+        {
+            parse_of(&args[0])
+        }
+        ExprKind::Try(expr) | ExprKind::Paren(expr) => parse_of(expr),
+        _ => None,
+    }
+}
+
+/// The map, key and value of `map.entry(key).or_insert(value)`.
+fn or_insert(expr: &ast::Expr) -> Option<(&ast::Expr, &ast::Expr, &ast::Expr)> {
+    match &expr.node {
+        ExprKind::MethodCall(method, _, args)
+            if is_named(&method.node, "or_insert") && args.len() == 2 =>
+        {
+            match &args[0].node {
+                ExprKind::MethodCall(method, _, entry)
+                    if is_named(&method.node, "entry") && entry.len() == 2 =>
+                {
+                    Some((&entry[0], &entry[1], &args[1]))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether `expr` might use or bind `name`, macros are assumed to.
+fn mentions(expr: &ast::Expr, name: ast::Name) -> bool {
+    struct Mentions(ast::Name, bool);
+    impl<'a> Visitor<'a> for Mentions {
+        fn visit_ident(&mut self, _: Span, ident: ast::Ident) {
+            self.1 |= ident.name == self.0;
+        }
+        fn visit_mac(&mut self, _: &'a ast::Mac) {
+            self.1 = true;
+        }
+    }
+    let mut mentions = Mentions(name, false);
+    mentions.visit_expr(expr);
+    mentions.1
+}
+
+/// `args` without the `None`s at the end.
+fn without_nones(mut args: &[P<Expr>]) -> &[P<Expr>] {
+    while let Some((last, rest)) = args.split_last() {
+        match &last.node {
+            ExprKind::Path(None, path) if path_name(path) == "None" => args = rest,
+            _ => break,
+        }
+    }
+    args
+}
+
+fn is_default(func: &ast::Expr) -> bool {
+    match &func.node {
+        ExprKind::Path(None, path) => {
+            path_name(path) == "Default::default"
+                || path_name(path) == "std::default::Default::default"
+        }
+        _ => false,
+    }
+}
+
+fn is_branching(expr: &ast::Expr) -> bool {
+    matches!(
+        expr.node,
+        ExprKind::If(..) | ExprKind::IfLet(..) | ExprKind::Match(..)
+    )
+}
+
+fn is_std_use(item: &ast::Item) -> bool {
+    match &item.node {
+        ItemKind::Use(view_path) => {
+            let path = view_path.node.path();
+            // use paths start at the crate root
+            let segments = &path.segments[path.is_global() as usize..];
+            segments.first().is_some_and(|segment| {
+                ["std", "core", "alloc"]
+                    .iter()
+                    .any(|name| is_named(&segment.identifier, name))
+            })
+        }
+        _ => false,
+    }
+}
+
+/// `use super::..` or `use self::..`, naming items lua already sees.
+fn is_super_use(item: &ast::Item) -> bool {
+    match &item.node {
+        ItemKind::Use(view_path) => view_path
+            .node
+            .path()
+            .segments
+            .first()
+            .is_some_and(|segment| {
+                is_named(&segment.identifier, "super") || is_named(&segment.identifier, "self")
+            }),
+        _ => false,
+    }
+}
+
+/// `lua` a level further in, unless it has long strings, whose lines
+/// can't move.
+fn indented(lua: &str) -> String {
+    if lua.contains("[[") || lua.contains("[=") {
+        return lua.to_string();
+    }
+    let lines = lua.lines().map(|line| {
+        if line.is_empty() {
+            String::new()
+        } else {
+            format!("  {}", line)
+        }
+    });
+    lines.collect::<Vec<_>>().join("\n") + "\n"
+}
+
+fn is_empty_block(stmt: &ast::Stmt) -> bool {
+    match &stmt.node {
+        StmtKind::Expr(expr) | StmtKind::Semi(expr) => match &expr.node {
+            ExprKind::Block(block) => block.stmts.is_empty(),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The tail expression of `block`, its value.
+fn block_value(block: &Block) -> Option<&ast::Expr> {
+    match &block.stmts.last()?.node {
+        StmtKind::Expr(expr) => Some(expr),
+        _ => None,
+    }
+}
+
+/// `cond` when the block ends in `if cond { break }`.
+fn post_condition(block: &Block) -> Option<&ast::Expr> {
+    let expr = match &block.stmts.last()?.node {
+        StmtKind::Expr(expr) | StmtKind::Semi(expr) => expr,
+        _ => return None,
+    };
+    match &expr.node {
+        ExprKind::If(cond, then, None) => match &then.stmts[..] {
+            [stmt] => match &stmt.node {
+                StmtKind::Expr(expr) | StmtKind::Semi(expr) => match expr.node {
+                    ExprKind::Break(None, None) => Some(cond),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_return(stmt: &ast::Stmt) -> bool {
+    match &stmt.node {
+        StmtKind::Expr(expr) | StmtKind::Semi(expr) => {
+            matches!(expr.node, ExprKind::Ret(_) | ExprKind::Break(..))
+        }
+        _ => false,
+    }
+}
+
+/// `(a, b)` when a comparator's body is just `a.cmp(b)`.
+fn comparison(body: &ast::Expr) -> Option<(&ast::Expr, &ast::Expr)> {
+    match &body.node {
+        ExprKind::Block(block) => match block.stmts.as_slice() {
+            [ast::Stmt {
+                node: StmtKind::Expr(expr),
+                ..
+            }] => comparison(expr),
+            _ => None,
+        },
+        ExprKind::MethodCall(method, _, args)
+            if is_named(&method.node, "unwrap") && args.len() == 1 =>
+        {
+            comparison(&args[0])
+        }
+        ExprKind::MethodCall(method, _, args)
+            if (is_named(&method.node, "cmp") || is_named(&method.node, "partial_cmp"))
+                && args.len() == 2 =>
+        {
+            Some((&args[0], strip_ref(&args[1])))
+        }
+        _ => None,
+    }
+}
+
+/// Splits an iterator chain into its source and the `map`, `filter`,
+/// `filter_map` and `take` calls on it, in the order they're applied.
+fn adapters(mut expr: &ast::Expr) -> (&ast::Expr, Vec<(String, &ast::Expr)>) {
+    let mut adapters = Vec::new();
+    while let ExprKind::MethodCall(method, _, args) = &expr.node {
+        let name = method.node.name.to_string();
+        if args.len() != 2 || !["map", "filter", "filter_map", "take"].contains(&&*name) {
+            break;
+        }
+        adapters.push((name, &*args[1]));
+        expr = &args[0];
+    }
+    adapters.reverse();
+    (expr, adapters)
+}
+
+/// Whether `expr` is an iterator chain `Generator::chain` can loop over.
+fn is_chain(expr: &ast::Expr) -> bool {
+    let (source, _) = adapters(expr);
+    is_iterator(source)
+        || bounded_range(source).is_some()
+        || repeated(source).is_some()
+        || once(source).is_some()
+}
+
+/// `x` in `std::iter::once(x)`.
+fn once(expr: &ast::Expr) -> Option<&ast::Expr> {
+    match &expr.node {
+        ExprKind::Call(func, args) if args.len() == 1 => match &func.node {
+            ExprKind::Path(None, path)
+                if ["once", "iter::once", "std::iter::once"].contains(&&*path_name(path)) =>
+            {
+                Some(&args[0])
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `x` in `std::iter::repeat(x)`.
+fn repeated(expr: &ast::Expr) -> Option<&ast::Expr> {
+    match &expr.node {
+        ExprKind::Call(func, args) if args.len() == 1 => match &func.node {
+            ExprKind::Path(None, path)
+                if ["repeat", "iter::repeat", "std::iter::repeat"].contains(&&*path_name(path)) =>
+            {
+                Some(&args[0])
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `expr` is obviously an iterator rather than an Option.
+fn is_iterator(expr: &ast::Expr) -> bool {
+    const ITERATORS: &[&str] = &[
+        "iter",
+        "into_iter",
+        "iter_mut",
+        "chars",
+        "bytes",
+        "lines",
+        "map",
+        "filter",
+        "enumerate",
+        "rev",
+        "skip",
+        "take",
+        "zip",
+        "chain",
+    ];
+    match &expr.node {
+        ExprKind::MethodCall(method, ..) => ITERATORS.iter().any(|name| method.node.name == *name),
+        _ => false,
+    }
+}
+
+/// Lua's prefixexp, the expressions that can be called or indexed
+/// without wrapping them in parentheses. Unsure guesses say no, parentheses
+/// never hurt.
+fn is_prefix_expr(lua: &str) -> bool {
+    if !lua.starts_with(|c: char| c == '_' || c == '(' || c.is_ascii_alphabetic()) {
+        return false;
+    }
+    // anything but names, fields and methods outside the brackets is an
+    // operator, or a string we don't look into
+    let mut depth = 0;
+    let mut chars = lua.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // brackets in strings inside the brackets don't count
+            '\'' | '"' if depth > 0 => {
+                while let Some(next) = chars.next() {
+                    match next {
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ if next == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            _ if depth > 0 => {}
+            '_' | '.' | ':' => {}
+            _ if c.is_alphanumeric() => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Joins the segments of a path, eg `String::new`.
+fn path_name(path: &ast::Path) -> String {
+    let names: Vec<String> = path
+        .segments
+        .iter()
+        .map(|segment| segment.identifier.name.to_string())
+        .collect();
+    names.join("::")
+}
+
+fn is_named(ident: &ast::Ident, name: &str) -> bool {
+    ident.name == name
+}
+
+enum FormatArg {
+    Next,
+    Index(usize),
+    Named(String),
+}
+
+/// A format string split into text and `{}` placeholders, with the
+/// placeholders already converted to string.format directives.
+enum Piece {
+    Text(String),
+    Arg {
+        arg: FormatArg,
+        directive: Option<String>,
+        // {:?}
+        debug: bool,
+        // whether the spec has an alignment, rust pads strings on the right
+        // and numbers on the left without one
+        aligned: bool,
+        // padding string.format can't do, applied after the directive
+        pad: Option<Pad>,
+    },
+}
+
+/// Centering or a fill other than spaces, `{:*^10}`.
+struct Pad {
+    fill: char,
+    align: char,
+    width: String,
+}
+
+fn format_pieces(fmt: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let inner: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                let (arg, spec) = match inner.find(':') {
+                    Some(i) => (&inner[..i], &inner[i + 1..]),
+                    None => (&inner[..], ""),
+                };
+                let arg = if arg.is_empty() {
+                    FormatArg::Next
+                } else if let Ok(i) = arg.parse() {
+                    FormatArg::Index(i)
+                } else {
+                    FormatArg::Named(arg.to_string())
+                };
+
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(text.split_off(0)));
+                }
+                let (directive, pad) = match directive(spec) {
+                    Some((directive, pad)) => (Some(directive), pad),
+                    None => (None, None),
+                };
+                pieces.push(Piece::Arg {
+                    arg,
+                    directive,
+                    debug: spec.ends_with('?'),
+                    aligned: spec.chars().take(2).any(|c| "<^>".contains(c)),
+                    pad,
+                });
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    pieces
+}
+
+/// Converts a format spec like `>5.2` (the part after the `:`) into a
+/// string.format directive like `%5.2f`, and the padding it can't do.
+fn directive(spec: &str) -> Option<(String, Option<Pad>)> {
+    let spec: Vec<char> = spec.chars().collect();
+    let mut i = 0;
+    let mut flags = String::new();
+
+    // [[fill]align], string.format only pads with spaces on either side
+    let (fill, align) = if spec.len() > 1 && "<^>".contains(spec[1]) {
+        i += 2;
+        (spec[0], Some(spec[1]))
+    } else if !spec.is_empty() && "<^>".contains(spec[0]) {
+        i += 1;
+        (' ', Some(spec[0]))
+    } else {
+        (' ', None)
+    };
+    let padded = align == Some('^') || fill != ' ';
+    if align == Some('<') && !padded {
+        flags.push('-');
+    }
+    for &flag in &['+', '#', '0'] {
+        if i < spec.len() && spec[i] == flag {
+            flags.push(flag);
+            i += 1;
+        }
+    }
+
+    let width: String = spec[i..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    i += width.len();
+
+    let mut precision = String::new();
+    if i < spec.len() && spec[i] == '.' {
+        precision.push('.');
+        i += 1;
+        let digits: String = spec[i..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        i += digits.len();
+        precision.push_str(&digits);
+    }
+
+    let conversion = match spec[i..].iter().collect::<String>().as_str() {
+        "x" | "X" | "o" | "e" | "E" => spec[i],
+        "" | "?" if !precision.is_empty() => 'f',
+        // zero padding and signs are for numbers, format_args makes it %g
+        // unless they're integers
+        "" | "?" if flags.contains('0') || flags.contains('+') => 'd',
+        "" | "?" => {
+            // {:#?} is pretty printing, not lua's alternate form
+            flags = flags.replace('#', "");
+            's'
+        }
+        _ => return None,
+    };
+
+    if padded && !width.is_empty() {
+        let pad = Pad {
+            fill,
+            align: align.unwrap(),
+            width,
+        };
+        return Some((format!("%{}{}{}", flags, precision, conversion), Some(pad)));
+    }
+    Some((
+        format!("%{}{}{}{}", flags, width, precision, conversion),
+        None,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lua `src` becomes, after `setup` sets the generator's options.
+    fn lua_with<F: FnOnce(&mut Generator)>(src: &str, setup: F) -> String {
+        let (src, labeled_blocks) = old_syntax(src);
+        let sess = ParseSess::new(FilePathMapping::empty());
+        let name = "test.rs".to_string();
+        let krate = parsed(syntax::parse::parse_crate_from_source_str(
+            name.clone(),
+            src.clone(),
+            &sess,
+        ));
+        let (comments, _) =
+            comments::gather_comments_and_literals(&sess, name, &mut src.as_bytes());
+        let mut generator = Generator::new(&sess);
+        generator.comments = comments;
+        generator.labeled_blocks = labeled_blocks;
+        setup(&mut generator);
+        generator.module(&krate.module);
+        generator.output()
+    }
+
+    /// Asserts `src` becomes `expected`, leading and trailing blank lines
+    /// aside.
+    fn check(src: &str, expected: &str) {
+        assert_eq!(lua_with(src, |_| {}).trim(), expected.trim());
+    }
+
+    /// What --check reports about `src`.
+    fn unsupported(src: &str) -> Vec<String> {
+        let (src, _) = old_syntax(src);
+        let sess = ParseSess::new(FilePathMapping::empty());
+        let name = "test.rs".to_string();
+        let krate = parsed(syntax::parse::parse_crate_from_source_str(name, src, &sess));
+        let mut generator = Generator::new(&sess);
+        generator.lenient = true;
+        generator.module(&krate.module);
+        generator
+            .unsupported
+            .into_iter()
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+
+    #[test]
+    fn write_to_string() {
+        check(
+            r#"
+fn f(n: i32) -> String {
+    let mut s = String::new();
+    write!(s, "{}:", n).unwrap();
+    writeln!(s, "{}", n);
+    s
+}
+"#,
+            r#"
+function f(n)
+  local s = ''
+  s = s .. string.format('%s:', tostring(n))
+  s = s .. string.format('%s\n', tostring(n))
+  return s
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn format_argument_missing() {
+        assert_eq!(
+            unsupported("fn f(a: i32) {\n    println!(\"{} {}\", a);\n}\n"),
+            ["unsupported format string without enough arguments: `\"{} {}\"`"]
+        );
+    }
+
+    #[test]
+    fn let_without_init() {
+        check(
+            r#"
+fn f(big: bool) -> i32 {
+    let x: i32;
+    if big {
+        x = 10;
+    } else {
+        x = 1;
+    }
+    return x;
+}
+"#,
+            r#"
+function f(big)
+  local x
+  if big then
+    x = 10
+  else
+    x = 1
+  end
+  return x
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn negative_literals() {
+        check(
+            r#"
+fn f(a: i32, b: i32) -> i32 {
+    let x = -5;
+    a - -b + x - -5
+}
+"#,
+            r#"
+function f(a, b)
+  local x = -5
+  return a - (-b) + x - (-5)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn nested_index() {
+        check(
+            r#"
+fn value_at(grid: &Vec<Vec<Cell>>, i: usize, j: usize) -> i32 {
+    grid[i][j].value + grid[i + 1][j - 1].value
+}
+"#,
+            r#"
+function value_at(grid, i, j)
+  return grid[i + 1][j + 1].value + grid[i + 2][j].value
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        check(
+            r#"
+fn between(a: i32, b: i32, c: i32) -> bool {
+    a < b && b < c || a == c
+}
+"#,
+            r#"
+function between(a, b, c)
+  return a < b and b < c or a == c
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn integer_division() {
+        check(
+            r#"
+fn halves(lo: usize, hi: usize, total: f64) -> f64 {
+    let mid = (lo + hi) / 2;
+    let n = 7 / 2;
+    let mut left = mid;
+    left /= 2;
+    total / 2.0
+}
+"#,
+            r#"
+function halves(lo, hi, total)
+  local mid = (lo + hi) // 2
+  local n = 7 // 2
+  local left = mid
+  left = left // 2
+  return total / 2.0
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn contains_searches_strings_and_scans_vectors() {
+        check(
+            r#"
+fn has(names: &[&str], name: &str, ids: Vec<u32>) -> bool {
+    names.contains(&name) && name.contains("x") && ids.contains(&3) && "abc".contains('b')
+}
+"#,
+            r#"
+local function __rua_contains(t, x)
+  for _, v in ipairs(t) do
+    if v == x then
+      return true
+    end
+  end
+  return false
+end
+
+function has(names, name, ids)
+  return __rua_contains(names, name) and (string.find(name, 'x', 1, true) ~= nil) and __rua_contains(ids, 3) and (string.find('abc', 'b', 1, true) ~= nil)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn empty_bodies() {
+        check(
+            r#"
+fn noop() {}
+
+fn idle(ready: bool) {
+    if ready {}
+    loop {}
+}
+"#,
+            r#"
+function noop()
+end
+
+function idle(ready)
+  if ready then
+  end
+  while true do
+  end
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn closure_returns_tuple() {
+        check(
+            r#"
+fn split() {
+    let halves = |n: i32| (n / 2, n - n / 2);
+    let (a, b) = halves(5);
+}
+"#,
+            r#"
+function split()
+  local halves = function(n) return n // 2, n - n // 2 end
+  local a, b = halves(5)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn control_flow_in_match_arms() {
+        check(
+            r#"
+fn total(v: &[i32]) -> i32 {
+    let mut sum = 0;
+    for x in v {
+        match x {
+            0 => continue,
+            -1 => break,
+            _ => sum += x,
+        }
+    }
+    sum
+}
+"#,
+            r#"
+function total(v)
+  local sum = 0
+  for _, x in ipairs(v) do
+    if x == 0 then
+      goto continue
+    elseif x == -1 then
+      break
+    else
+      sum = sum + x
+    end
+    ::continue::
+  end
+  return sum
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn string_patterns() {
+        check(
+            r#"
+fn run(cmd: &str) -> i32 {
+    match cmd {
+        "quit" | "exit" => 0,
+        "it's" => 1,
+        "say \"hi\"\n" => 2,
+        _ => 3,
+    }
+}
+"#,
+            r#"
+function run(cmd)
+  if cmd == 'quit' or cmd == 'exit' then
+    return 0
+  elseif cmd == 'it\'s' then
+    return 1
+  elseif cmd == 'say "hi"\n' then
+    return 2
+  else
+    return 3
+  end
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn mut_and_ref_bindings() {
+        check(
+            r#"
+fn bindings(pair: (i32, i32), v: Vec<i32>) -> i32 {
+    let mut x = 0;
+    let (ref a, ref mut b) = pair;
+    let total = v.iter().fold(0, |mut acc, n| {
+        acc += n;
+        acc
+    });
+    x += a + b;
+    x + total
+}
+"#,
+            r#"
+function bindings(pair, v)
+  local x = 0
+  local a, b = table.unpack(pair)
+  local total = (function()
+    local __rua_f1 = function(acc, n)
+      acc = acc + n
+      return acc
+    end
+    local __rua_acc = 0
+    for _, __rua_x in ipairs(v) do
+      __rua_acc = __rua_f1(__rua_acc, __rua_x)
+    end
+    return __rua_acc
+  end)()
+  x = x + (a + b)
+  return x + total
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn nested_closures() {
+        check(
+            r#"
+fn adder(x: i32) -> impl Fn(i32) -> Box<Fn(i32) -> i32> {
+    move |y| Box::new(move |z| x + y + z)
+}
+
+fn curry() -> i32 {
+    let add = |x: i32| move |y: i32| x + y;
+    let nested = |x: i32| {
+        let double = |y: i32| y * 2;
+        move |z: i32| double(x) + z
+    };
+    add(1)(2) + nested(3)(4)
+}
+"#,
+            r#"
+function adder(x)
+  return function(y) return function(z) return x + y + z end end
+end
+
+function curry()
+  local add = function(x) return function(y) return x + y end end
+  local nested = function(x)
+    local double = function(y) return y * 2 end
+    return function(z) return double(x) + z end
+  end
+  return add(1)(2) + nested(3)(4)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn inclusive_ranges() {
+        check(
+            r#"
+fn sums(n: i32) -> i32 {
+    let mut total = 0;
+    for i in 1..=n {
+        total += i;
+    }
+    for i in 0..n {
+        total -= i;
+    }
+    let all: Vec<i32> = (1..=n).collect();
+    let some: Vec<i32> = (1..n).collect();
+    total
+}
+"#,
+            r#"
+function sums(n)
+  local total = 0
+  for i = 1, n do
+    total = total + i
+  end
+  for i = 0, n - 1 do
+    total = total - i
+  end
+  local all = (function()
+    local __rua_t = {}
+    for __rua_x = 1, n do
+      __rua_t[#__rua_t + 1] = __rua_x
+    end
+    return __rua_t
+  end)()
+  local some = (function()
+    local __rua_t = {}
+    for __rua_x = 1, n - 1 do
+      __rua_t[#__rua_t + 1] = __rua_x
+    end
+    return __rua_t
+  end)()
+  return total
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn raw_identifiers_are_left_alone() {
+        let (src, _) = old_syntax("let r#const = 1..=2;\nr#const {}\nr#\"..=\"#");
+        assert_eq!(src, "let r#const = 1...2;\nr#const {}\nr#\"..=\"#");
+    }
+
+    #[test]
+    fn do_while_loops() {
+        check(
+            r#"
+fn count_pairs(mut n: i32) -> i32 {
+    let mut count = 0;
+    loop {
+        count += 1;
+        n -= 2;
+        if n <= 0 {
+            break;
+        }
+    }
+    count
+}
+"#,
+            r#"
+function count_pairs(n)
+  local count = 0
+  repeat
+    count = count + 1
+    n = n - 2
+  until n <= 0
+  return count
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn lua_globals_are_reported() {
+        assert_eq!(
+            unsupported("fn pairs() {}\nstruct table;\nfn f() {\n    let next = 1;\n}\n"),
+            [
+                "unsupported name of a lua global the output uses: `fn pairs() {}`",
+                "unsupported name of a lua global the output uses: `struct table;`",
+            ]
+        );
+    }
+
+    #[test]
+    fn fn_names_as_values() {
+        check(
+            r#"
+fn double(x: i32) -> i32 {
+    x * 2
+}
+
+fn apply<F: Fn(i32) -> i32>(f: F, x: i32) -> i32 {
+    f(x)
+}
+
+fn main() {
+    let f = double;
+    println!("{}", apply(double, 3) + f(1));
+}
+"#,
+            r#"
+function double(x)
+  return x * 2
+end
+
+function apply(f, x)
+  return f(x)
+end
+
+function main()
+  local f = double
+  print(string.format('%s', tostring(apply(double, 3) + f(1))))
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn let_from_match_and_if() {
+        check(
+            r#"
+fn describe_let(n: i32) -> &'static str {
+    let size = match n {
+        0 => "none",
+        1..=9 => "few",
+        _ => "many",
+    };
+    let parity = if n % 2 == 0 { "even" } else { "odd" };
+    size
+}
+"#,
+            r#"
+function describe_let(n)
+  local size
+  if n == 0 then
+    size = 'none'
+  elseif n >= 1 and n <= 9 then
+    size = 'few'
+  else
+    size = 'many'
+  end
+  local parity
+  if n % 2 == 0 then
+    parity = 'even'
+  else
+    parity = 'odd'
+  end
+  return size
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn valueless_early_returns() {
+        check(
+            r#"
+fn greet(done: bool) {
+    if done {
+        return;
+    }
+    println!("hi");
+    return;
+    println!("unreachable");
+}
+"#,
+            r#"
+function greet(done)
+  if done then return end
+  print('hi')
+  do return end
+  print('unreachable')
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn associated_function_calls() {
+        check(
+            r#"
+struct Foo {
+    n: i32,
+}
+
+impl Foo {
+    fn helper(n: i32) -> i32 {
+        n + 1
+    }
+
+    fn new() -> Foo {
+        Foo { n: Self::helper(1) }
+    }
+
+    fn bump(&mut self) {
+        self.n = Foo::helper(self.n);
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.n = 0;
+    }
+}
+"#,
+            r#"
+Foo = {}
+Foo.__index = Foo
+
+function Foo.helper(n)
+  return n + 1
+end
+
+function Foo.new()
+  return setmetatable({n = Foo.helper(1)}, Foo)
+end
+
+function Foo:bump()
+  self.n = Foo.helper(self.n)
+  self:reset()
+end
+
+function Foo:reset()
+  self.n = 0
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn loop_bodies_do_not_return() {
+        check(
+            r#"
+fn spin(mut n: i32) -> i32 {
+    while n > 10 {
+        n -= 1
+    }
+    for i in 0..3 {
+        println!("{}", i)
+    }
+    loop {
+        n += 1;
+        if n > 20 {
+            break;
+        }
+    }
+    n
+}
+"#,
+            r#"
+function spin(n)
+  while n > 10 do
+    n = n - 1
+  end
+  for i = 0, 2 do
+    print(string.format('%s', tostring(i)))
+  end
+  repeat
+    n = n + 1
+  until n > 20
+  return n
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn shadowing_changes_type() {
+        check(
+            r#"
+fn f() -> i32 {
+    let x = "5";
+    let x = 1;
+    let y = x + 2;
+    let s = 5;
+    let s = String::from("a");
+    let t = s + "b";
+    y
+}
+"#,
+            r#"
+function f()
+  local x = '5'
+  local x = 1
+  local y = x + 2
+  local s = 5
+  local s = 'a'
+  local t = s .. 'b'
+  return y
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn shadowing() {
+        check(
+            r#"
+fn parse() -> i32 {
+    let x = "5";
+    let x: i32 = x.parse().unwrap();
+    let x = x / 2;
+    x
+}
+"#,
+            r#"
+function parse()
+  local x = '5'
+  local x = assert(math.tointeger(tonumber(x)), 'called unwrap on nil')
+  local x = x // 2
+  return x
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn mem_take_and_replace() {
+        check(
+            r#"
+fn step(state: &mut i32, log: &mut Vec<i32>) -> i32 {
+    let entries = std::mem::take(log);
+    std::mem::replace(state, 0) + entries.len() as i32
+}
+"#,
+            r#"
+function step(state, log)
+  local entries = (function()
+    local __rua_old = log
+    log = {}
+    return __rua_old
+  end)()
+  return (function()
+    local __rua_old = state
+    state = 0
+    return __rua_old
+  end)() + #entries
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn generic_impls() {
+        check(
+            r#"
+struct Container<T> {
+    items: Vec<T>,
+}
+
+impl<T: Clone> Container<T> {
+    fn get(&self, i: usize) -> &T {
+        &self.items[i]
+    }
+
+    fn map<U, F: Fn(&T) -> U>(&self, f: F) -> Vec<U> {
+        self.items.iter().map(f).collect()
+    }
+}
+
+impl<T> Default for Container<T> {
+    fn default() -> Self {
+        Container { items: Vec::new() }
+    }
+}
+"#,
+            r#"
+Container = {}
+Container.__index = Container
+
+function Container:get(i)
+  return self.items[i + 1]
+end
+
+function Container:map(f)
+  return (function()
+    local __rua_t = {}
+    for _, __rua_x in ipairs(self.items) do
+      local __rua_x = f(__rua_x)
+      __rua_t[#__rua_t + 1] = __rua_x
+    end
+    return __rua_t
+  end)()
+end
+
+function Container.default()
+  return setmetatable({items = {}}, Container)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn nested_control_flow_indentation() {
+        check(
+            r#"
+fn classify(v: &[i32]) -> i32 {
+    let mut total = 0;
+    for x in v {
+        match *x {
+            0 => {
+                if total > 3 {
+                    while total > 0 {
+                        total -= 1;
+                    }
+                } else {
+                    total += 1;
+                }
+            }
+            n => loop {
+                let kind = match n % 3 {
+                    0 => if n > 9 { "big" } else { "small" },
+                    _ => "odd",
+                };
+                println!("{}", kind);
+                break;
+            },
+        }
+    }
+    let r = if total > 1 {
+        match total {
+            2 => 20,
+            _ => {
+                let t = total * 2;
+                t
+            }
+        }
+    } else {
+        0
+    };
+    r
+}
+"#,
+            r#"
+function classify(v)
+  local total = 0
+  for _, x in ipairs(v) do
+    if x == 0 then
+      if total > 3 then
+        while total > 0 do
+          total = total - 1
+        end
+      else
+        total = total + 1
+      end
+    else
+      local n = x
+      while true do
+        local kind
+        do
+          local __rua_v = n % 3
+          if __rua_v == 0 then
+            if n > 9 then
+              kind = 'big'
+            else
+              kind = 'small'
+            end
+          else
+            kind = 'odd'
+          end
+        end
+        print(string.format('%s', tostring(kind)))
+        break
+      end
+    end
+  end
+  local r
+  if total > 1 then
+    if total == 2 then
+      r = 20
+    else
+      local t = total * 2
+      r = t
+    end
+  else
+    r = 0
+  end
+  return r
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn nested_indentation_in_values() {
+        check(
+            r#"
+fn show(n: i32) {
+    println!("{}", match n {
+        0 => "zero",
+        _ => {
+            let mut s = "x";
+            if n > 5 {
+                for _ in 0..n {
+                    s = "y";
+                }
+            }
+            s
+        }
+    });
+}
+"#,
+            r#"
+function show(n)
+  print(string.format('%s', tostring((function()
+    if n == 0 then
+      return 'zero'
+    else
+      local s = 'x'
+      if n > 5 then
+        for _ = 0, n - 1 do
+          s = 'y'
+        end
+      end
+      return s
+    end
+  end)())))
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn noop_attributes_are_dropped() {
+        check(
+            r#"
+#[inline]
+#[must_use]
+fn double(x: i32) -> i32 {
+    x * 2
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct P {
+    x: i32,
+}
+
+#[allow(unused)]
+impl P {
+    #[inline(always)]
+    #[doc(hidden)]
+    fn get(&self) -> i32 {
+        #[allow(unused)]
+        let y = self.x;
+        y
+    }
+}
+"#,
+            r#"
+function double(x)
+  return x * 2
+end
+
+P = {}
+P.__index = P
+
+function P:get()
+  local y = self.x
+  return y
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn guard_clauses() {
+        check(
+            r#"
+fn first_even(v: &[i32]) -> i32 {
+    if v.is_empty() {
+        return -1;
+    }
+    for x in v {
+        if x % 2 == 1 {
+            continue;
+        }
+        if *x > 100 {
+            break;
+        }
+        if *x > 0 {
+            return *x;
+        }
+    }
+    0
+}
+"#,
+            r#"
+function first_even(v)
+  if (#v == 0) then return -1 end
+  for _, x in ipairs(v) do
+    if x % 2 == 1 then goto continue end
+    if x > 100 then break end
+    if x > 0 then return x end
+    ::continue::
+  end
+  return 0
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn associated_types_are_skipped() {
+        check(
+            r#"
+struct Counter {
+    n: i32,
+}
+
+impl Iterator for Counter {
+    type Item = i32;
+
+    fn next(&mut self) -> Option<i32> {
+        self.n += 1;
+        Some(self.n)
+    }
+}
+"#,
+            r#"
+Counter = {}
+Counter.__index = Counter
+
+function Counter:next()
+  self.n = self.n + 1
+  return self.n
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn impl_macros_are_reported() {
+        assert_eq!(
+            unsupported("struct Foo;\nimpl Foo {\n    type T = i32;\n    m!();\n}\n"),
+            ["unsupported impl item: `m!();`"]
+        );
+    }
+
+    #[test]
+    fn compound_while_conditions() {
+        check(
+            r#"
+fn scan(v: &[i32], n: usize, flag: bool) -> usize {
+    let mut i = 0;
+    let mut done = false;
+    while i < n && flag {
+        i += 1;
+    }
+    while i < v.len() && !done || v[i] == 0 {
+        done = v[i] > 3;
+        i += 1;
+    }
+    i
+}
+"#,
+            r#"
+function scan(v, n, flag)
+  local i = 0
+  local done = false
+  while i < n and flag do
+    i = i + 1
+  end
+  while i < #v and not done or v[i + 1] == 0 do
+    done = v[i + 1] > 3
+    i = i + 1
+  end
+  return i
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn statement_and_value_match() {
+        check(
+            r#"
+fn report(n: i32) -> i32 {
+    match n {
+        0 => println!("zero"),
+        _ => println!("some"),
+    }
+    if n > 1 {
+        println!("many");
+    }
+    let x = if n > 1 { 2 } else { 3 };
+    x + match n {
+        0 => 1,
+        _ => 2,
+    }
+}
+"#,
+            r#"
+function report(n)
+  if n == 0 then
+    print('zero')
+  else
+    print('some')
+  end
+  if n > 1 then
+    print('many')
+  end
+  local x
+  if n > 1 then
+    x = 2
+  else
+    x = 3
+  end
+  return x + (function()
+    if n == 0 then
+      return 1
+    else
+      return 2
+    end
+  end)()
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn single_arm_match() {
+        check(
+            r#"
+fn compute() -> i32 {
+    4
+}
+
+fn single() -> i32 {
+    match compute() {
+        result => result * 2,
+    }
+}
+
+fn single_stmt() {
+    match compute() {
+        n => println!("{}", n),
+    }
+}
+"#,
+            r#"
+function compute()
+  return 4
+end
+
+function single()
+  do
+    local result = compute()
+    return result * 2
+  end
+end
+
+function single_stmt()
+  do
+    local n = compute()
+    print(string.format('%s', tostring(n)))
+  end
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn identifier_casing() {
+        check(
+            r#"
+struct HttpRequest {
+    status_code: i32,
+}
+
+impl HttpRequest {
+    fn is_ok(&self) -> bool {
+        self.status_code == 200
+    }
+}
+
+fn parse_http_request(status_code: i32) -> HttpRequest {
+    let mut camelCase = HttpRequest { status_code };
+    camelCase
+}
+"#,
+            r#"
+HttpRequest = {}
+HttpRequest.__index = HttpRequest
+
+function HttpRequest:is_ok()
+  return self.status_code == 200
+end
+
+function parse_http_request(status_code)
+  local camelCase = setmetatable({status_code = status_code}, HttpRequest)
+  return camelCase
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn non_ascii_identifiers_are_reported() {
+        assert_eq!(
+            unsupported("fn größe() -> i32 {\n    let ß = 1;\n    ß\n}\n"),
+            [
+                "unsupported identifier lua can't spell: `fn größe() -> i32 {`",
+                "unsupported identifier lua can't spell: `ß`",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_in_desugared_loops() {
+        check(
+            r#"
+fn drain(mut stack: Vec<i32>, items: &[(i32, i32)]) -> i32 {
+    let mut total = 0;
+    while let Some(top) = stack.pop() {
+        if top < 0 {
+            continue;
+        }
+        if top > 100 {
+            break;
+        }
+        total += top;
+    }
+    for (a, b) in items {
+        if *a == 0 {
+            continue;
+        }
+        if *b == 0 {
+            break;
+        }
+        total += a * b;
+    }
+    for i in 0..10 {
+        if i == total {
+            break;
+        }
+    }
+    total
+}
+"#,
+            r#"
+function drain(stack, items)
+  local total = 0
+  while true do
+    local top = table.remove(stack)
+    if top == nil then
+      break
+    end
+    if top < 0 then goto continue end
+    if top > 100 then break end
+    total = total + top
+    ::continue::
+  end
+  for _, __rua_x in ipairs(items) do
+    local a = __rua_x[1]
+    local b = __rua_x[2]
+    if a == 0 then goto continue end
+    if b == 0 then break end
+    total = total + a * b
+    ::continue::
+  end
+  for i = 0, 9 do
+    if i == total then break end
+  end
+  return total
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn typed_closures_with_block_bodies() {
+        check(
+            r#"
+fn apply() -> Vec<i32> {
+    let f = |x: i32| -> i32 {
+        let y = x * 2;
+        y
+    };
+    let g = |x: i32| -> i32 { x + 1 };
+    let v = vec![1, 2];
+    v.iter().map(|x: &i32| -> i32 { f(g(*x)) }).collect()
+}
+"#,
+            r#"
+function apply()
+  local f = function(x)
+    local y = x * 2
+    return y
+  end
+  local g = function(x) return x + 1 end
+  local v = {1, 2}
+  return (function()
+    local __rua_f1 = function(x) return f(g(x)) end
+    local __rua_t = {}
+    for _, __rua_x in ipairs(v) do
+      local __rua_x = __rua_f1(__rua_x)
+      __rua_t[#__rua_t + 1] = __rua_x
+    end
+    return __rua_t
+  end)()
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn result_locals_keep_their_error() {
+        check(
+            r#"
+fn check(n: i32) -> Result<i32, String> {
+    let r = if n > 0 { Ok(n) } else { Err(format!("bad {}", n)) };
+    if let Err(e) = r {
+        println!("{}", e);
+    }
+    let failed: Result<i32, String> = Err("no".to_string());
+    let copy = failed;
+    if matches!(copy, Ok(_)) {
+        return copy;
+    }
+    let last = match r {
+        Ok(v) if v > 10 => Ok(v),
+        Ok(_) => Err("small".to_string()),
+        Err(e) => Err(e),
+    };
+    last
+}
+"#,
+            r#"
+function check(n)
+  local r, __rua_err_r = (function()
+    if n > 0 then
+      return n
+    else
+      return nil, string.format('bad %s', tostring(n))
+    end
+  end)()
+  if __rua_err_r ~= nil then
+    local e = __rua_err_r
+    print(string.format('%s', tostring(e)))
+  end
+  local failed, __rua_err_failed = nil, tostring('no')
+  local copy, __rua_err_copy = failed, __rua_err_failed
+  if (__rua_err_copy == nil) then return copy, __rua_err_copy end
+  local last, __rua_err_last
+  if __rua_err_r == nil and r > 10 then
+    local v = r
+    last, __rua_err_last = v
+  elseif __rua_err_r == nil then
+    last, __rua_err_last = nil, tostring('small')
+  else
+    local e = __rua_err_r
+    last, __rua_err_last = nil, e
+  end
+  return last, __rua_err_last
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn match_on_results() {
+        check(
+            r#"
+fn parse(s: &str) -> Result<i32, String> {
+    if s.is_empty() {
+        return Err(format!("empty: {}", s));
+    }
+    Ok(1)
+}
+
+fn run(s: &str) -> String {
+    let r = parse(s);
+    match r {
+        Ok(n) => format!("{}", n),
+        Err(e) => e,
+    }
+}
+
+fn run2(s: &str) -> i32 {
+    match parse(s) {
+        Ok(n) => n,
+        Err(_) => 0,
+    }
+}
+"#,
+            r#"
+function parse(s)
+  if (#s == 0) then return nil, string.format('empty: %s', tostring(s)) end
+  return 1
+end
+
+function run(s)
+  local r, __rua_err_r = parse(s)
+  if __rua_err_r == nil then
+    local n = r
+    return string.format('%s', tostring(n))
+  else
+    local e = __rua_err_r
+    return e
+  end
+end
+
+function run2(s)
+  do
+    local __rua_v, __rua_err = parse(s)
+    if __rua_err == nil then
+      local n = __rua_v
+      return n
+    else
+      return 0
+    end
+  end
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn results_in_other_places_are_reported() {
+        assert_eq!(
+            unsupported("fn f(r: Result<i32, i32>) -> i32 {\n    match r {\n        Ok(n) => n,\n        Err(e) => e,\n    }\n}\n"),
+            ["unsupported Ok or Err pattern on a place that isn't a local holding a Result: `r`"]
+        );
+    }
+
+    #[test]
+    fn module_paths_in_struct_literals() {
+        check(
+            r#"
+mod geo {
+    pub struct Point {
+        pub x: i32,
+        pub y: i32,
+    }
+
+    pub mod shapes {
+        pub struct Line {
+            pub a: i32,
+        }
+
+        pub fn unit() -> Line {
+            self::Line { a: 1 }
+        }
+    }
+
+    pub fn origin() -> Point {
+        Point { x: 0, y: 0 }
+    }
+}
+
+fn make(x: i32, y: i32) -> geo::Point {
+    let line = geo::shapes::Line { a: x };
+    geo::Point { x, y: y + line.a }
+}
+"#,
+            r#"
+geo = {}
+do
+  local Point, shapes, origin
+  Point = {}
+  Point.__index = Point
+
+  shapes = {}
+  do
+    local Line, unit
+    Line = {}
+    Line.__index = Line
+
+    function unit()
+      return setmetatable({a = 1}, Line)
+    end
+
+    shapes.Line = Line
+    shapes.unit = unit
+  end
+
+  function origin()
+    return setmetatable({x = 0, y = 0}, Point)
+  end
+
+  geo.Point = Point
+  geo.shapes = shapes
+  geo.origin = origin
+end
+
+function make(x, y)
+  local line = setmetatable({a = x}, geo.shapes.Line)
+  return setmetatable({x = x, y = y + line.a}, geo.Point)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn err_in_returns_and_lets() {
+        check(
+            r#"
+fn takes(r: Result<i32, String>, n: i32) -> i32 {
+    n
+}
+
+fn check(n: i32) -> Result<i32, String> {
+    if n > 0 {
+        return Err("negative".to_string());
+    }
+    let r = Err("no".to_string());
+    let f = |x: i32| -> Result<i32, i32> { Err(x) };
+    if let Err(e) = Err(3) {
+        println!("{}", e);
+    }
+    r
+}
+"#,
+            r#"
+function takes(r, n)
+  return n
+end
+
+function check(n)
+  if n > 0 then return nil, tostring('negative') end
+  local r, __rua_err_r = nil, tostring('no')
+  local f = function(x) return nil, x end
+  do
+    local __rua_v, __rua_err = nil, 3
+    if __rua_err ~= nil then
+      local e = __rua_err
+      print(string.format('%s', tostring(e)))
+    end
+  end
+  return r, __rua_err_r
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn err_in_other_places_is_reported() {
+        let src = "fn f(r: Result<i32, i32>, n: i32) -> i32 {\n    n\n}\n\nfn g() -> i32 {\n    let v = vec![Ok(1), Err(2)];\n    f(Err(3), 5)\n}\n";
+        assert_eq!(
+            unsupported(src),
+            [
+                "unsupported Err anywhere but a return or let: `Err(2)`",
+                "unsupported Err anywhere but a return or let: `Err(3)`",
+            ]
+        );
+    }
+
+    #[test]
+    fn free_fns_and_methods_are_kept_apart() {
+        check(
+            r#"
+struct Stack {
+    top: i32,
+}
+
+impl Stack {
+    fn get(&self) -> i32 {
+        self.top
+    }
+
+    fn split(&self) -> (i32, i32) {
+        (self.top, 0)
+    }
+}
+
+fn get() -> (i32, i32) {
+    (1, 2)
+}
+
+fn pair(a: (i32, i32), b: i32) -> i32 {
+    b
+}
+
+fn use_both(s: &Stack) -> i32 {
+    let (a, b) = Stack::split(s);
+    pair(get(), s.get()) + pair(s.split(), a)
+}
+"#,
+            r#"
+Stack = {}
+Stack.__index = Stack
+
+function Stack:get()
+  return self.top
+end
+
+function Stack:split()
+  return self.top, 0
+end
+
+function get()
+  return 1, 2
+end
+
+function pair(a, b)
+  return b
+end
+
+function use_both(s)
+  local a, b = Stack.split(s)
+  return pair({get()}, s:get()) + pair({s:split()}, a)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn free_result_fns_and_methods_are_kept_apart() {
+        check(
+            r#"
+struct Token {
+    text: String,
+}
+
+impl Token {
+    fn parse_num(&self) -> i32 {
+        0
+    }
+
+    fn checked(&self) -> Result<i32, String> {
+        Ok(self.parse_num())
+    }
+
+    fn both(&self) -> i32 {
+        let c = self.checked();
+        0
+    }
+}
+
+fn parse_num() -> Result<i32, String> {
+    Ok(1)
+}
+
+fn use_token(t: &Token) -> i32 {
+    let n = t.parse_num();
+    let k = parse_num();
+    let c = Token::checked(t);
+    n
+}
+"#,
+            r#"
+Token = {}
+Token.__index = Token
+
+function Token:parse_num()
+  return 0
+end
+
+function Token:checked()
+  return self:parse_num()
+end
+
+function Token:both()
+  local c, __rua_err_c = self:checked()
+  return 0
+end
+
+function parse_num()
+  return 1
+end
+
+function use_token(t)
+  local n = t:parse_num()
+  local k, __rua_err_k = parse_num()
+  local c, __rua_err_c = Token.checked(t)
+  return n
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn try_keeps_evaluation_order() {
+        check(
+            r#"
+fn side() -> i32 {
+    1
+}
+
+fn g() -> Option<i32> {
+    Some(2)
+}
+
+fn f(v: &[i32], x: i32) -> Option<i32> {
+    let y = side() + g()?;
+    let z = x + g()?;
+    let w = pick(side(), v[0], g()?, side());
+    Some(y + z + w)
+}
+
+fn pick(a: i32, b: i32, c: i32, d: i32) -> i32 {
+    a
+}
+"#,
+            r#"
+function side()
+  return 1
+end
+
+function g()
+  return 2
+end
+
+function f(v, x)
+  local __rua_t1 = side()
+  local __rua_t2 = g()
+  if __rua_t2 == nil then return nil end
+  local y = __rua_t1 + __rua_t2
+  local __rua_t3 = g()
+  if __rua_t3 == nil then return nil end
+  local z = x + __rua_t3
+  local __rua_t4 = side()
+  local __rua_t5 = g()
+  if __rua_t5 == nil then return nil end
+  local w = pick(__rua_t4, v[1], __rua_t5, side())
+  return y + z + w
+end
+
+function pick(a, b, c, d)
+  return a
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn sign_and_zero_flags_on_floats() {
+        check(
+            r#"
+fn show(n: i32, f: f64) {
+    println!("{:+} {:05} {:+}", n, n, 3);
+    println!("{:+} {:05} {:+}", f, f, 1.5);
+    println!("{:>+8}|{:08.2}|{:*^+9}", f, f, f);
+}
+"#,
+            r#"
+local function __rua_pad(s, fill, align, width)
+  local n = width - utf8.len(s)
+  if n <= 0 then
+    return s
+  elseif align == '<' then
+    return s .. fill:rep(n)
+  elseif align == '>' then
+    return fill:rep(n) .. s
+  end
+  return fill:rep(n // 2) .. s .. fill:rep(n - n // 2)
+end
+
+function show(n, f)
+  print(string.format('%+d %05d %+d', n, n, 3))
+  print(string.format('%+.14g %05.14g %+.14g', f, f, 1.5))
+  print(string.format('%+8.14g|%08.2f|%s', f, f, __rua_pad(string.format('%+.14g', f), '*', '^', 9)))
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn module_keeps_shebang_first() {
+        let lua = lua_with("#!/usr/bin/env rua\n// hi\npub fn f() {}\n", |g| {
+            g.lua_module = true;
+            g.header = "-- gen\n".to_string();
+        });
+        assert_eq!(
+            lua.trim(),
+            r#"
+#!/usr/bin/env rua
+-- gen
+local M = {}
+local f
+
+-- hi
+function f()
+end
+
+M.f = f
+return M
+"#
+            .trim()
+        );
+    }
+
+    #[test]
+    fn module_returns_pub_items() {
+        let lua = lua_with(
+            r#"
+pub const LIMIT: i64 = 10;
+
+fn helper(x: i64) -> i64 {
+    x * 2
+}
+
+pub fn double(x: i64) -> i64 {
+    helper(x)
+}
+"#,
+            |g| g.lua_module = true,
+        );
+        assert_eq!(
+            lua.trim(),
+            r#"
+local M = {}
+local LIMIT, helper, double
+
+LIMIT = 10
+
+function helper(x)
+  return x * 2
+end
+
+function double(x)
+  return helper(x)
+end
+
+M.LIMIT = LIMIT
+M.double = double
+return M
+"#
+            .trim()
+        );
+    }
+
+    #[test]
+    fn block_comments_with_closing_brackets() {
+        check(
+            r#"
+/* a[b[0]] is the first */
+fn f() {}
+"#,
+            r#"
+--[=[ a[b[0]] is the first ]=]
+function f()
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn map_methods_index_the_table() {
+        check(
+            r#"
+use std::collections::HashMap;
+
+fn ages() -> HashMap<String, i32> {
+    let mut ages = HashMap::new();
+    ages.insert("ann".to_string(), 31);
+    ages.insert("bob".to_string(), 27);
+    ages.remove("bob");
+    if let Some(age) = ages.get("ann") {
+        println!("{}", age);
+    }
+    println!("{} {}", ages.len(), ages.contains_key("bob"));
+    ages
+}
+
+fn bump(counts: &mut HashMap<usize, i32>, key: usize) {
+    counts.insert(key, counts.get(&key).copied().unwrap_or(0) + 1);
+}
+"#,
+            r#"
+local function __rua_count(t)
+  local n = 0
+  for _ in pairs(t) do
+    n = n + 1
+  end
+  return n
+end
+
+local function __rua_unwrap_or(value, default)
+  if value == nil then
+    return default
+  end
+  return value
+end
+
+function ages()
+  local ages = {}
+  ages[tostring('ann')] = 31
+  ages[tostring('bob')] = 27
+  ages['bob'] = nil
+  do
+    local __rua_v = ages['ann']
+    if __rua_v ~= nil then
+      local age = __rua_v
+      print(string.format('%s', tostring(age)))
+    end
+  end
+  print(string.format('%s %s', tostring(__rua_count(ages)), tostring((ages['bob'] ~= nil))))
+  return ages
+end
+
+function bump(counts, key)
+  counts[key] = (__rua_unwrap_or(counts[key], 0) + 1)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn other_map_methods_are_reported() {
+        assert_eq!(
+            unsupported("use std::collections::HashMap;\nfn f(m: &mut HashMap<i32, i32>) {\n    let old = m.insert(1, 2);\n    let ks = m.keys();\n}\n"),
+            [
+                "unsupported map update as a value: `m.insert(1, 2)`",
+                "unsupported map method: `m.keys()`",
+            ]
+        );
+    }
+
+    #[test]
+    fn slices_copy_shallow_and_vecs_deep() {
+        check(
+            r#"
+fn snapshot(buffer: &[i32], grid: &Vec<Vec<i32>>) {
+    let saved = buffer.to_vec();
+    let again = buffer.clone();
+    let board = grid.clone();
+}
+"#,
+            r#"
+local function __rua_copy(t)
+  return table.move(t, 1, #t, 1, {})
+end
+
+local function __rua_clone(value, seen)
+  if type(value) ~= 'table' then
+    return value
+  end
+  seen = seen or {}
+  if seen[value] then
+    return seen[value]
+  end
+  local copy = {}
+  seen[value] = copy
+  for k, v in pairs(value) do
+    copy[k] = __rua_clone(v, seen)
+  end
+  return setmetatable(copy, getmetatable(value))
+end
+
+function snapshot(buffer, grid)
+  local saved = __rua_copy(buffer)
+  local again = __rua_copy(buffer)
+  local board = __rua_clone(grid)
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn let_types_say_what_parse_gives() {
+        check(
+            r#"
+fn read(s: &str) -> i64 {
+    let y: i32 = "42".parse().unwrap();
+    let z: f64 = s.parse().unwrap_or(0.5);
+    let n: usize = s.trim().parse().expect("a count");
+    y as i64 + n as i64
+}
+"#,
+            r#"
+local function __rua_unwrap_or(value, default)
+  if value == nil then
+    return default
+  end
+  return value
+end
+
+function read(s)
+  local y = assert(math.tointeger(tonumber('42')), 'called unwrap on nil')
+  local z = __rua_unwrap_or(tonumber(s), 0.5)
+  local n = assert(math.tointeger(tonumber(s:match('^%s*(.-)%s*$'))), 'a count')
+  return y + n
+end
+"#,
+        );
+    }
+
+    #[test]
+    fn position_is_0_based() {
+        check(
+            r#"
+fn index_of(names: &[&str], target: &str) -> Option<usize> {
+    names.iter().position(|name| *name == target)
+}
+"#,
+            r#"
+function index_of(names, target)
+  return (function()
+    local __rua_f1 = function(name) return name == target end
+    local __rua_i = 0
+    for _, __rua_x in ipairs(names) do
+      if __rua_f1(__rua_x) then
+        return __rua_i
+      end
+      __rua_i = __rua_i + 1
+    end
+  end)()
+end
+"#,
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Lua helpers for things lua has no builtin for. The generator emits each
+//! one at the top of the output the first time generated code calls it.
+
+/// Dumps tables recursively for `{:?}`, strings are quoted like rust does.
+/// Tables with a `__tostring`, like structs deriving Debug, format themselves.
+pub const DEBUG_FMT: &str = r#"local function __rua_debug_fmt(value, seen)
+  if type(value) == 'string' then
+    return string.format('%q', value)
+  elseif type(value) ~= 'table' then
+    return tostring(value)
+  end
+  local mt = getmetatable(value)
+  if mt and mt.__tostring then
+    return tostring(value)
+  end
+  seen = seen or {}
+  if seen[value] then
+    return '...'
+  end
+  seen[value] = true
+  local parts, n = {}, 0
+  for i, v in ipairs(value) do
+    parts[i] = __rua_debug_fmt(v, seen)
+    n = i
+  end
+  local keys = {}
+  for k in pairs(value) do
+    if type(k) ~= 'number' or k < 1 or k > n or k % 1 ~= 0 then
+      keys[#keys + 1] = k
+    end
+  end
+  table.sort(keys, function(a, b) return tostring(a) < tostring(b) end)
+  for _, k in ipairs(keys) do
+    parts[#parts + 1] = tostring(k) .. ' = ' .. __rua_debug_fmt(value[k], seen)
+  end
+  seen[value] = nil
+  if #keys == 0 then
+    return '[' .. table.concat(parts, ', ') .. ']'
+  end
+  return '{' .. table.concat(parts, ', ') .. '}'
+end
+"#;
+
+/// `s.split(sep)` as a table of the parts, `sep` is matched literally.
+pub const SPLIT: &str = r#"local function __rua_split(s, sep)
+  local parts, start = {}, 1
+  if sep == '' then
+    parts[1] = ''
+    for c in s:gmatch(utf8.charpattern) do
+      parts[#parts + 1] = c
+    end
+    parts[#parts + 1] = ''
+    return parts
+  end
+  while true do
+    local i, j = string.find(s, sep, start, true)
+    if not i then
+      parts[#parts + 1] = string.sub(s, start)
+      return parts
+    end
+    parts[#parts + 1] = string.sub(s, start, i - 1)
+    start = j + 1
+  end
+end
+"#;
+
+/// Deep copies tables for derived `clone`, copies keep their metatable.
+/// `seen` maps the tables copied so far to their copies, so a table
+/// reachable twice is copied once and cycles end.
+pub const CLONE: &str = r#"local function __rua_clone(value, seen)
+  if type(value) ~= 'table' then
+    return value
+  end
+  seen = seen or {}
+  if seen[value] then
+    return seen[value]
+  end
+  local copy = {}
+  seen[value] = copy
+  for k, v in pairs(value) do
+    copy[k] = __rua_clone(v, seen)
+  end
+  return setmetatable(copy, getmetatable(value))
+end
+"#;
+
+/// Shallow copies the elements of a slice for `to_vec` and `clone`.
+pub const COPY: &str = r#"local function __rua_copy(t)
+  return table.move(t, 1, #t, 1, {})
+end
+"#;
+
+/// `opt.map(f)`, checking for nil instead of using `and`/`or` so `f` can
+/// return false.
+pub const MAP: &str = r#"local function __rua_map(value, f)
+  if value == nil then
+    return nil
+  end
+  return f(value)
+end
+"#;
+
+/// `opt.map_or(default, f)`, like `__rua_map` with a default for nil.
+pub const MAP_OR: &str = r#"local function __rua_map_or(value, default, f)
+  if value == nil then
+    return default
+  end
+  return f(value)
+end
+"#;
+
+/// `opt.map_or_else(default, f)`, calling `default` only when there's no
+/// value.
+pub const MAP_OR_ELSE: &str = r#"local function __rua_map_or_else(value, default, f)
+  if value == nil then
+    return default()
+  end
+  return f(value)
+end
+"#;
+
+/// `opt.unwrap_or(default)`, `or` would also replace false.
+pub const UNWRAP_OR: &str = r#"local function __rua_unwrap_or(value, default)
+  if value == nil then
+    return default
+  end
+  return value
+end
+"#;
+
+/// `opt.or_else(f)`, calling `f` only when there's no value.
+pub const OR_ELSE: &str = r#"local function __rua_or_else(value, f)
+  if value == nil then
+    return f()
+  end
+  return value
+end
+"#;
+
+/// `opt.and(other)`, `and` would also replace false.
+pub const AND: &str = r#"local function __rua_and(value, other)
+  if value == nil then
+    return nil
+  end
+  return other
+end
+"#;
+
+/// `{:*^10}`, padding `s` to `width` characters with `fill`, which
+/// string.format can't do. Centering puts the odd one on the right.
+pub const PAD: &str = r#"local function __rua_pad(s, fill, align, width)
+  local n = width - utf8.len(s)
+  if n <= 0 then
+    return s
+  elseif align == '<' then
+    return s .. fill:rep(n)
+  elseif align == '>' then
+    return fill:rep(n) .. s
+  end
+  return fill:rep(n // 2) .. s .. fill:rep(n - n // 2)
+end
+"#;
+
+/// `map.entry(key).or_insert(value)`, the value at `key` after giving it
+/// `value` if it had none.
+pub const OR_INSERT: &str = r#"local function __rua_or_insert(map, key, value)
+  if map[key] == nil then
+    map[key] = value
+  end
+  return map[key]
+end
+"#;
+
+/// `s.chars().rev()`, a table of the characters of `s` from last to first.
+/// Going back through the bytes would split multibyte characters.
+pub const REV_CHARS: &str = r#"local function __rua_rev_chars(s)
+  local chars = {}
+  for c in s:gmatch(utf8.charpattern) do
+    table.insert(chars, c)
+  end
+  local n = #chars
+  for i = 1, n // 2 do
+    chars[i], chars[n - i + 1] = chars[n - i + 1], chars[i]
+  end
+  return chars
+end
+"#;
+
+/// `s.chars().nth(n)`, nil past the end like rust's None.
+pub const NTH_CHAR: &str = r#"local function __rua_nth_char(s, n)
+  local start = utf8.offset(s, n + 1)
+  if start == nil or start > #s then
+    return nil
+  end
+  return s:match(utf8.charpattern, start)
+end
+"#;
+
+/// `set.len()`, sets keep their elements in the keys so `#` doesn't count
+/// them.
+pub const COUNT: &str = r#"local function __rua_count(t)
+  local n = 0
+  for _ in pairs(t) do
+    n = n + 1
+  end
+  return n
+end
+"#;
+
+/// `v.contains(&x)` of a vector, sets and strings have their own.
+pub const CONTAINS: &str = r#"local function __rua_contains(t, x)
+  for _, v in ipairs(t) do
+    if v == x then
+      return true
+    end
+  end
+  return false
+end
+"#;
+
+/// `a.chain(b)`, a table of the elements of `a` followed by those of `b`.
+pub const CHAIN: &str = r#"local function __rua_chain(first, second)
+  local chained = {}
+  for _, v in ipairs(first) do
+    chained[#chained + 1] = v
+  end
+  for _, v in ipairs(second) do
+    chained[#chained + 1] = v
+  end
+  return chained
+end
+"#;
+
+/// A range used as a value, a table of the numbers from `first` to `last`.
+pub const RANGE: &str = r#"local function __rua_range(first, last)
+  local range = {}
+  for i = first, last do
+    range[#range + 1] = i
+  end
+  return range
+end
+"#;
+
+/// `a.cmp(b)`, an Ordering of -1, 0 or 1. Works on anything with `<`, like
+/// numbers, strings and tables with `__lt`.
+pub const CMP: &str = r#"local function __rua_cmp(a, b)
+  if a < b then
+    return -1
+  elseif b < a then
+    return 1
+  end
+  return 0
+end
+"#;
+
+/// `v.sort_by(f)`, table.sort wants a less than instead of an Ordering.
+pub const SORT_BY: &str = r#"local function __rua_sort_by(t, f)
+  table.sort(t, function(a, b)
+    return f(a, b) < 0
+  end)
+end
+"#;
+
+/// `v.sort_by_key(f)`.
+pub const SORT_BY_KEY: &str = r#"local function __rua_sort_by_key(t, f)
+  table.sort(t, function(a, b)
+    return f(a) < f(b)
+  end)
+end
+"#;
+
+/// `value[first..=last]` of a table or string, to the end without `last`.
+pub const SLICE: &str = r#"local function __rua_slice(value, first, last)
+  last = last or #value
+  if type(value) == 'string' then
+    return value:sub(first, last)
+  end
+  return table.move(value, first, last, 1, {})
+end
+"#;
+
+/// Runs the `__rua_tests` table of `{name, fn, should_panic}` emitted with
+/// --emit-tests, exiting with 1 if any fail.
+pub const TEST_RUNNER: &str = r#"local __rua_failed = 0
+for _, test in ipairs(__rua_tests) do
+  local ok, err = pcall(test[2])
+  if ok == test[3] then
+    __rua_failed = __rua_failed + 1
+    print('test ' .. test[1] .. ' ... FAILED')
+    if not ok then
+      print('  ' .. tostring(err))
+    end
+  else
+    print('test ' .. test[1] .. ' ... ok')
+  end
+end
+print(string.format('\ntest result: %d passed; %d failed', #__rua_tests - __rua_failed, __rua_failed))
+if __rua_failed > 0 then
+  os.exit(1)
+end
+"#;
+
+/// Every helper by name, so lua written inside the generator can mention
+/// helpers and get them emitted.
+pub const HELPERS: &[(&str, &str)] = &[
+    ("__rua_debug_fmt", DEBUG_FMT),
+    ("__rua_pad", PAD),
+    ("__rua_split", SPLIT),
+    ("__rua_clone", CLONE),
+    ("__rua_copy", COPY),
+    ("__rua_map", MAP),
+    ("__rua_map_or", MAP_OR),
+    ("__rua_map_or_else", MAP_OR_ELSE),
+    ("__rua_unwrap_or", UNWRAP_OR),
+    ("__rua_or_else", OR_ELSE),
+    ("__rua_and", AND),
+    ("__rua_or_insert", OR_INSERT),
+    ("__rua_rev_chars", REV_CHARS),
+    ("__rua_nth_char", NTH_CHAR),
+    ("__rua_count", COUNT),
+    ("__rua_contains", CONTAINS),
+    ("__rua_chain", CHAIN),
+    ("__rua_range", RANGE),
+    ("__rua_slice", SLICE),
+    ("__rua_cmp", CMP),
+    ("__rua_sort_by", SORT_BY),
+    ("__rua_sort_by_key", SORT_BY_KEY),
+];